@@ -1,5 +1,5 @@
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
 use serde::{Deserialize, Serialize};
@@ -49,6 +49,12 @@ impl ReadOk {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct Topology {
+    r#type: String,
+    topology: HashMap<String, Vec<String>>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct TopologyOk {
     r#type: String,
@@ -63,16 +69,19 @@ impl TopologyOk {
 
 fn main() -> serde_json::Result<()> {
     let mut server = Server::new();
-    let adj_nodes = server.sender.node_ids.clone();
+    // Everyone is adjacent until Maelstrom sends a topology to replace them with
+    let adj_nodes = Rc::new(RefCell::new(server.sender.node_ids.clone()));
+    let adj_nodes_topology = adj_nodes.clone();
     let messages = Rc::new(RefCell::new(vec![]));
     let messages_read = messages.clone();
+    let messages_topology = messages.clone();
     let mut messages_set = HashSet::new();
     server.handle("broadcast", move |sender, msg| {
         let msg: Message<Broadcast> = msg.cast()?;
         let message = msg.body.message;
         if messages_set.insert(message) {
             messages.borrow_mut().push(message);
-            for adj_node in adj_nodes.iter() {
+            for adj_node in adj_nodes.borrow().iter() {
                 sender.rpc(adj_node, &Broadcast::new(message), |_, _| Ok(()))?;
             }
         }
@@ -82,7 +91,18 @@ fn main() -> serde_json::Result<()> {
         sender.respond(msg, &ReadOk::new(messages_read.borrow().clone()))
     });
     server.handle("topology", move |sender, msg| {
-        // Just ignore the topology lmao
+        let topology: Message<Topology> = msg.cast()?;
+        // A topology that leaves us out keeps the neighbors we have
+        if let Some(neighbors) = topology.body.topology.get(&sender.node_id) {
+            let mut adj_nodes = adj_nodes_topology.borrow_mut();
+            // New neighbors are sent everything, since they missed what was broadcast so far
+            for adj_node in neighbors.iter().filter(|n| !adj_nodes.contains(n)) {
+                for &message in messages_topology.borrow().iter() {
+                    sender.rpc(adj_node, &Broadcast::new(message), |_, _| Ok(()))?;
+                }
+            }
+            *adj_nodes = neighbors.clone();
+        }
         sender.respond(msg, &TopologyOk::new())
     });
     server.serve()
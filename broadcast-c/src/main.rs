@@ -1,4 +1,5 @@
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::{cell::RefCell, rc::Rc};
 
 use serde::{Deserialize, Serialize};
@@ -60,6 +61,12 @@ impl ReadOk {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct Topology {
+    r#type: String,
+    topology: HashMap<String, Vec<String>>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct TopologyOk {
     r#type: String,
@@ -75,18 +82,21 @@ impl TopologyOk {
 fn main() -> serde_json::Result<()> {
     let mut server = Server::new();
     let sender = server.sender.clone();
-    let adj_nodes_thread = sender.lock().unwrap().node_ids.clone();
+    // Everyone is adjacent until Maelstrom sends a topology to replace them with
+    let adj_nodes = Arc::new(Mutex::new(sender.lock().unwrap().node_ids.clone()));
+    let adj_nodes_thread = adj_nodes.clone();
+    let adj_nodes_topology = adj_nodes.clone();
     std::thread::spawn(move || loop {
         std::thread::sleep(std::time::Duration::from_secs(5));
-        for adj_node in adj_nodes_thread.iter() {
+        let adj_nodes = adj_nodes_thread.lock().unwrap().clone();
+        for adj_node in adj_nodes.iter() {
             sender
                 .lock()
                 .unwrap()
-                .send_body(adj_node, &Read::new())
+                .send_body(adj_node, Read::new())
                 .expect("Error sending refresh");
         }
     });
-    let adj_nodes = server.sender.lock().unwrap().node_ids.clone();
     let messages: Rc<RefCell<Vec<u64>>> = Rc::new(RefCell::new(vec![]));
     let messages_read = messages.clone();
     let messages_read_ok = messages.clone();
@@ -96,8 +106,8 @@ fn main() -> serde_json::Result<()> {
         let mut messages = messages.borrow_mut();
         if !messages.contains(&message) {
             messages.push(message);
-            for adj_node in adj_nodes.iter() {
-                sender.send_body(adj_node, &Broadcast::new(message))?;
+            for adj_node in adj_nodes.lock().unwrap().iter() {
+                sender.send_body(adj_node, Broadcast::new(message))?;
             }
         }
         sender.respond(&msg, &BroadcastOk::new())
@@ -117,7 +127,11 @@ fn main() -> serde_json::Result<()> {
         Ok(())
     });
     server.handle("topology", move |sender, msg| {
-        // Just ignore the topology lmao
+        let topology: Message<Topology> = msg.cast()?;
+        // A topology that leaves us out keeps the neighbors we have
+        if let Some(neighbors) = topology.body.topology.get(&sender.node_id) {
+            *adj_nodes_topology.lock().unwrap() = neighbors.clone();
+        }
         sender.respond(msg, &TopologyOk::new())
     });
     server.serve()
@@ -1,7 +1,6 @@
 use std::{
     collections::{hash_map::DefaultHasher, HashMap},
     sync::{Arc, Mutex},
-    time::Instant,
 };
 
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
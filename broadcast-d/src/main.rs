@@ -35,19 +35,20 @@ enum P {
     TopologyOk {},
 }
 
-type Context = (Sender, Vec<u64>);
+/// The sender, the values seen so far, and the neighbors to pass them on to
+type Context = (Sender, Vec<u64>, Vec<String>);
 
 fn main() -> serde_json::Result<()> {
     let (server, sender) = server::init()?;
+    // Until Maelstrom sends a topology to replace them
     let neighbors = sender.sane_neighbors();
-    let thread_neighbors = neighbors.clone();
-    let context: Arc<Mutex<Context>> = Arc::new(Mutex::new((sender, vec![])));
+    let context: Arc<Mutex<Context>> = Arc::new(Mutex::new((sender, vec![], neighbors)));
     let thread_context = context.clone();
     std::thread::spawn(move || loop {
         std::thread::sleep(std::time::Duration::from_secs(5));
         let mut ctx = thread_context.lock().unwrap();
-        let (sender, values) = ctx.deref_mut();
-        for neighbor in thread_neighbors.iter() {
+        let (sender, values, neighbors) = ctx.deref_mut();
+        for neighbor in neighbors.iter() {
             sender
                 .send(
                     neighbor,
@@ -61,7 +62,7 @@ fn main() -> serde_json::Result<()> {
     loop {
         let message: Message<P> = server.read_message()?;
         let mut ctx = context.lock().unwrap();
-        let (sender, values) = ctx.deref_mut();
+        let (sender, values, neighbors) = ctx.deref_mut();
         match message.body.fields {
             P::Broadcast { value } => {
                 if !values.contains(&value) {
@@ -105,14 +106,20 @@ fn main() -> serde_json::Result<()> {
                     }
                 }
             }
-            P::Topology { .. } => {
-                // let new_neighbors = topology
-                //     .get(&sender.node_id)
-                //     .expect("This node is not in the topology");
-                // neighbors.clear();
-                // for neighbor in new_neighbors {
-                //     neighbors.push(neighbor.to_string());
-                // }
+            P::Topology { ref topology } => {
+                // A topology that leaves us out keeps the neighbors we have
+                if let Some(new_neighbors) = topology.get(&sender.node_id) {
+                    // New neighbors get everything now rather than at the next refresh
+                    for neighbor in new_neighbors.iter().filter(|n| !neighbors.contains(n)) {
+                        sender.send(
+                            neighbor,
+                            &P::Fyi {
+                                values: values.clone(),
+                            },
+                        )?;
+                    }
+                    *neighbors = new_neighbors.clone();
+                }
                 sender.respond(&message, &P::TopologyOk {})?
             }
             P::TopologyOk {} => {}
@@ -0,0 +1,147 @@
+//! Simulate 20 seconds of broadcasts at 100 ops/sec over 25 nodes with 100ms of latency per hop,
+//! forwarding through per-neighbor batches the same way main.rs does, for a range of batch
+//! windows. Run with `cargo bench --bench batching`.
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+#[allow(dead_code)]
+#[path = "../src/topology.rs"]
+mod topology;
+
+const NODES: usize = 25;
+const LATENCY_MS: u64 = 100;
+const RATE: u64 = 100;
+const DURATION_MS: u64 = 20_000;
+const BATCH_SIZE: usize = 100;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Event {
+    Broadcast {
+        node: usize,
+        value: usize,
+    },
+    Deliver {
+        to: usize,
+        from: usize,
+        values: Vec<usize>,
+    },
+    Flush {
+        node: usize,
+        neighbor: usize,
+    },
+}
+
+struct Simulation {
+    graph: Vec<Vec<usize>>,
+    window: u64,
+    queue: BinaryHeap<Reverse<(u64, Event)>>,
+    /// When each node first saw each value
+    seen: Vec<HashMap<usize, u64>>,
+    batches: HashMap<(usize, usize), Vec<usize>>,
+    messages: usize,
+}
+
+impl Simulation {
+    fn learn(&mut self, now: u64, node: usize, value: usize, from: Option<usize>) {
+        if self.seen[node].insert(value, now).is_some() {
+            return;
+        }
+        for neighbor in self.graph[node].clone() {
+            if Some(neighbor) == from {
+                continue;
+            }
+            let batch = self.batches.entry((node, neighbor)).or_default();
+            batch.push(value);
+            if batch.len() == 1 {
+                self.queue.push(Reverse((
+                    now + self.window,
+                    Event::Flush { node, neighbor },
+                )));
+            }
+            if batch.len() >= BATCH_SIZE || self.window == 0 {
+                self.flush(now, node, neighbor);
+            }
+        }
+    }
+    fn flush(&mut self, now: u64, node: usize, neighbor: usize) {
+        if let Some(values) = self.batches.remove(&(node, neighbor)) {
+            self.messages += 1;
+            let deliver = Event::Deliver {
+                to: neighbor,
+                from: node,
+                values,
+            };
+            self.queue.push(Reverse((now + LATENCY_MS, deliver)));
+        }
+    }
+    fn run(&mut self) {
+        while let Some(Reverse((now, event))) = self.queue.pop() {
+            match event {
+                Event::Broadcast { node, value } => self.learn(now, node, value, None),
+                Event::Deliver { to, from, values } => {
+                    for value in values {
+                        self.learn(now, to, value, Some(from));
+                    }
+                }
+                Event::Flush { node, neighbor } => self.flush(now, node, neighbor),
+            }
+        }
+    }
+}
+
+fn main() {
+    let node_ids: Vec<String> = (0..NODES).map(|i| format!("n{}", i)).collect();
+    let strategy = topology::from_config("sane");
+    let graph: Vec<Vec<usize>> = node_ids
+        .iter()
+        .map(|node| {
+            strategy
+                .neighbors(node, &node_ids)
+                .iter()
+                .map(|n| node_ids.iter().position(|m| m == n).unwrap())
+                .collect()
+        })
+        .collect();
+    let ops = (DURATION_MS * RATE / 1000) as usize;
+    println!(
+        "{:>10} {:>12} {:>12} {:>12}",
+        "window", "msgs-per-op", "median (ms)", "max (ms)"
+    );
+    for window in [0, 25, 50, 100, 150, 200, 400] {
+        let mut simulation = Simulation {
+            graph: graph.clone(),
+            window,
+            queue: BinaryHeap::new(),
+            seen: vec![HashMap::new(); NODES],
+            batches: HashMap::new(),
+            messages: 0,
+        };
+        for value in 0..ops {
+            let at = value as u64 * 1000 / RATE;
+            let broadcast = Event::Broadcast {
+                node: value % NODES,
+                value,
+            };
+            simulation.queue.push(Reverse((at, broadcast)));
+        }
+        simulation.run();
+        // Latency is how long after the broadcast the value reached each node
+        let mut latencies: Vec<u64> = simulation
+            .seen
+            .iter()
+            .flat_map(|seen| {
+                seen.iter()
+                    .map(|(&value, &at)| at - value as u64 * 1000 / RATE)
+            })
+            .collect();
+        assert_eq!(latencies.len(), ops * NODES);
+        latencies.sort_unstable();
+        println!(
+            "{:>10} {:>12.1} {:>12} {:>12}",
+            format!("{}ms", window),
+            simulation.messages as f64 / ops as f64,
+            latencies[latencies.len() / 2],
+            latencies[latencies.len() - 1],
+        );
+    }
+}
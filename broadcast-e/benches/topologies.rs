@@ -0,0 +1,110 @@
+//! Simulate flooding a broadcast over each topology strategy at 25 nodes, with every hop taking
+//! 100ms like `--latency 100`. Run with `cargo bench --bench topologies`.
+use std::collections::{HashMap, VecDeque};
+
+#[allow(dead_code)]
+#[path = "../src/topology.rs"]
+mod topology;
+
+const NODES: usize = 25;
+const LATENCY_MS: usize = 100;
+
+/// Maelstrom's default grid topology, for the "maelstrom" strategy
+fn grid(node_ids: &[String]) -> HashMap<String, Vec<String>> {
+    let side = (node_ids.len() as f64).sqrt().ceil() as usize;
+    let mut topology = HashMap::new();
+    for (i, node) in node_ids.iter().enumerate() {
+        let (row, col) = (i / side, i % side);
+        let mut neighbors = vec![];
+        if col > 0 {
+            neighbors.push(node_ids[i - 1].clone());
+        }
+        if col + 1 < side && i + 1 < node_ids.len() {
+            neighbors.push(node_ids[i + 1].clone());
+        }
+        if row > 0 {
+            neighbors.push(node_ids[i - side].clone());
+        }
+        if i + side < node_ids.len() {
+            neighbors.push(node_ids[i + side].clone());
+        }
+        topology.insert(node.clone(), neighbors);
+    }
+    topology
+}
+
+/// Flood from `origin` the same way main.rs does: forward to every neighbor except the one we
+/// heard it from. Returns the hop count to reach each node and the number of messages sent.
+fn flood(graph: &[Vec<usize>], origin: usize) -> (Vec<Option<usize>>, usize) {
+    let mut hops = vec![None; graph.len()];
+    let mut messages = 0;
+    let mut queue = VecDeque::from([(origin, None)]);
+    hops[origin] = Some(0);
+    while let Some((node, from)) = queue.pop_front() {
+        for &neighbor in graph[node].iter().filter(|&&n| Some(n) != from) {
+            messages += 1;
+            if hops[neighbor].is_none() {
+                hops[neighbor] = Some(hops[node].unwrap() + 1);
+                queue.push_back((neighbor, Some(node)));
+            }
+        }
+    }
+    (hops, messages)
+}
+
+fn main() {
+    let node_ids: Vec<String> = (0..NODES).map(|i| format!("n{}", i)).collect();
+    println!(
+        "{:<12} {:>8} {:>8} {:>12} {:>12} {:>12}",
+        "topology", "degree", "diameter", "msgs-per-op", "median (ms)", "max (ms)"
+    );
+    for config in [
+        "sane",
+        "maelstrom",
+        "ring",
+        "hypercube",
+        "tree:2",
+        "tree:4",
+        "star:1",
+        "star:3",
+        "random:4",
+        "random:6",
+        "maelstrom+ring",
+    ] {
+        let mut strategy = topology::from_config(config);
+        strategy.provided(grid(&node_ids));
+        let graph: Vec<Vec<usize>> = node_ids
+            .iter()
+            .map(|node| {
+                strategy
+                    .neighbors(node, &node_ids)
+                    .iter()
+                    .map(|n| node_ids.iter().position(|m| m == n).unwrap())
+                    .collect()
+            })
+            .collect();
+        let degree = graph.iter().map(Vec::len).sum::<usize>() as f64 / NODES as f64;
+        let mut all_hops = vec![];
+        let mut messages = 0;
+        for origin in 0..NODES {
+            let (hops, sent) = flood(&graph, origin);
+            messages += sent;
+            all_hops.extend(hops);
+        }
+        if all_hops.iter().any(Option::is_none) {
+            println!("{:<12} {:>8.1} disconnected", config, degree);
+            continue;
+        }
+        let mut all_hops: Vec<usize> = all_hops.into_iter().flatten().collect();
+        all_hops.sort_unstable();
+        println!(
+            "{:<12} {:>8.1} {:>8} {:>12.1} {:>12} {:>12}",
+            config,
+            degree,
+            all_hops[all_hops.len() - 1],
+            messages as f64 / NODES as f64,
+            all_hops[all_hops.len() / 2] * LATENCY_MS,
+            all_hops[all_hops.len() - 1] * LATENCY_MS,
+        );
+    }
+}
@@ -0,0 +1,49 @@
+//! Compare the ValueSet against the plain Vec it replaced, at 100k values.
+//! Run with `cargo bench`.
+use std::time::Instant;
+
+#[allow(dead_code)]
+#[path = "../src/values.rs"]
+mod values;
+
+const COUNT: u64 = 100_000;
+
+fn main() {
+    // Values arrive mostly in order, with some duplicates from gossip
+    let incoming: Vec<u64> = (0..COUNT).chain((0..COUNT).step_by(3)).collect();
+
+    let start = Instant::now();
+    let mut vec: Vec<u64> = vec![];
+    for value in incoming.iter() {
+        if !vec.contains(value) {
+            vec.push(*value);
+        }
+    }
+    println!("Vec ingest:      {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let mut set = values::ValueSet::default();
+    for value in incoming.iter() {
+        set.insert(*value, *value);
+    }
+    println!("ValueSet ingest: {:?}", start.elapsed());
+    assert_eq!(vec.as_slice(), set.as_slice());
+
+    let start = Instant::now();
+    let json = serde_json::to_string(&vec).unwrap();
+    println!(
+        "Vec read:        {:?}, {} bytes",
+        start.elapsed(),
+        json.len()
+    );
+
+    let start = Instant::now();
+    let runs = values::to_runs(set.as_slice());
+    let json = serde_json::to_string(&runs).unwrap();
+    println!(
+        "Runs read:       {:?}, {} bytes",
+        start.elapsed(),
+        json.len()
+    );
+    assert_eq!(values::from_runs(&runs).unwrap().len(), vec.len());
+}
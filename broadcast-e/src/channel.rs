@@ -0,0 +1,971 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::Result;
+
+use crate::payload::Payload;
+use crate::plumtree::Plumtree;
+use crate::propagation::{Propagation, PropagationSummary, Stamp};
+use crate::reads::{self, PendingRead, ReadOkRef, Wait};
+use crate::reconcile::{Index, RangeDigest, Step};
+use crate::server::{Message, Sender};
+use crate::values::ValueSet;
+use crate::{Topical, P};
+
+/// Bounds on how long to wait for a neighbor to acknowledge forwarded values, and what to use
+/// before the neighbor has answered any pings
+const MIN_RETRY_TIMEOUT: Duration = Duration::from_millis(200);
+const DEFAULT_RETRY_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_RETRY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long an acknowledgement waits for a message to the same peer to ride along on, before it
+/// is sent by itself. Peers allow for this on top of the round trip before retrying.
+const ACK_DELAY: Duration = Duration::from_millis(50);
+
+/// How every channel spreads its values
+pub struct Settings {
+    pub batch_window: Duration,
+    pub batch_size: usize,
+    /// Whether anti-entropy compares digests instead of sending unacknowledged values
+    pub digest_sync: bool,
+    /// Only set in plumtree mode, otherwise every value is flooded to every neighbor
+    pub graft_timeout: Option<Duration>,
+}
+
+/// The parts of the node that a channel sends through
+pub struct Link<'a> {
+    pub sender: &'a mut Sender,
+    pub neighbors: &'a [String],
+    pub settings: &'a Settings,
+    pub topic: Option<String>,
+}
+
+impl Link<'_> {
+    pub fn send<T: Payload>(&mut self, to: &str, fields: P<T>) -> Result<()> {
+        self.sender.send(
+            to,
+            Topical {
+                topic: self.topic.clone(),
+                inner: fields,
+            },
+        )
+    }
+    /// Send, returning the msg_id so that the reply can be matched up
+    fn send_tracked<T: Payload>(&mut self, to: &str, fields: P<T>) -> Result<u64> {
+        let message = self.sender.message(
+            to,
+            Topical {
+                topic: self.topic.clone(),
+                inner: fields,
+            },
+        )?;
+        self.sender.send_message(&message)?;
+        Ok(message.body.msg_id.expect("No msg_id???"))
+    }
+    /// Respond to a message on this link's topic, by its sender and msg_id
+    fn reply<F: Serialize>(&mut self, to: &str, in_reply_to: Option<u64>, fields: F) -> Result<()> {
+        let mut message = self.sender.message(
+            to,
+            Topical {
+                topic: self.topic.clone(),
+                inner: fields,
+            },
+        )?;
+        message.body.in_reply_to = in_reply_to;
+        self.sender.send_message(&message)
+    }
+}
+
+/// A broadcast_to_peers that has not been acknowledged yet
+struct Unacked {
+    neighbor: String,
+    keys: Vec<u64>,
+    sent_at: Instant,
+    attempts: u32,
+}
+
+/// One independent broadcast topic, with its own values and gossip state. Values are tracked by
+/// their Payload::key everywhere except the value set itself.
+pub struct Channel<T> {
+    values: ValueSet<T>,
+    /// When each value arrived, in the same order as the values
+    arrivals: Vec<Instant>,
+    pending_reads: Vec<PendingRead>,
+    propagation: Propagation,
+    /// The keys indexed by hash, for digest anti-entropy
+    index: Index,
+    /// The keys each neighbor is known to have, either because they sent them to us or
+    /// because they acknowledged our gossip. Kept for former neighbors too, since they only
+    /// ever gain keys and may become neighbors again.
+    known: HashMap<String, HashSet<u64>>,
+    /// Gossip awaiting an acknowledgement, keyed by msg_id
+    pending_fyis: HashMap<u64, (String, Vec<u64>)>,
+    /// Keys waiting to be forwarded to each neighbor, and when the oldest one was queued
+    batches: HashMap<String, (Instant, Vec<u64>)>,
+    plumtree: Option<Plumtree>,
+    /// Forwarded keys awaiting an acknowledgement, keyed by msg_id
+    unacked: HashMap<u64, Unacked>,
+    /// The msg_ids we owe each peer an acknowledgement for, and when the oldest arrived
+    owed_acks: HashMap<String, (Instant, Vec<u64>)>,
+}
+
+impl<T: Payload> Channel<T> {
+    pub fn new(neighbors: &[String], settings: &Settings) -> Channel<T> {
+        Channel {
+            values: ValueSet::default(),
+            arrivals: vec![],
+            pending_reads: vec![],
+            propagation: Propagation::default(),
+            index: Index::default(),
+            known: HashMap::new(),
+            pending_fyis: HashMap::new(),
+            batches: HashMap::new(),
+            plumtree: settings
+                .graft_timeout
+                .map(|timeout| Plumtree::new(neighbors.to_vec(), timeout)),
+            unacked: HashMap::new(),
+            owed_acks: HashMap::new(),
+        }
+    }
+    /// Add a value along with the stamp it arrived with, returning its key if it was new
+    fn insert(&mut self, value: T, stamp: Option<Stamp>) -> Option<u64> {
+        let key = value.key();
+        if let Some(plumtree) = self.plumtree.as_mut() {
+            plumtree.received(key);
+        }
+        if self.values.insert(key, value) {
+            self.arrivals.push(Instant::now());
+            self.index.insert(key);
+            self.propagation.first_seen(key, stamp);
+            Some(key)
+        } else {
+            None
+        }
+    }
+    pub fn propagation_stats(&self) -> PropagationSummary {
+        self.propagation.summary()
+    }
+    fn learned_from(&mut self, neighbor: &str, keys: &[u64]) {
+        self.known
+            .entry(neighbor.to_string())
+            .or_default()
+            .extend(keys);
+    }
+    fn lookup(&self, keys: &[u64]) -> Vec<T> {
+        keys.iter()
+            .filter_map(|key| self.values.get(key))
+            .cloned()
+            .collect()
+    }
+    /// A value arrived from a client, or from a peer when `from` is set. Forwards it if it was
+    /// new, and returns whether it was.
+    fn broadcast(
+        &mut self,
+        link: &mut Link,
+        value: T,
+        stamp: Option<Stamp>,
+        from: Option<&str>,
+    ) -> Result<bool> {
+        match self.insert(value, stamp) {
+            Some(key) => self.forward(link, key, from).map(|_| true),
+            None => Ok(false),
+        }
+    }
+    /// A client broadcast a value through this node
+    pub fn submit(&mut self, link: &mut Link, value: T) -> Result<()> {
+        self.broadcast(link, value, Some(Stamp::origin()), None)
+            .map(|_| ())
+    }
+    /// Answer a read now if nothing needs waiting for, otherwise once answer_reads finds it
+    /// satisfied or timed out
+    pub fn read<F>(
+        &mut self,
+        link: &mut Link,
+        request: &Message<F>,
+        wait: Option<Wait>,
+        timeout: Duration,
+    ) -> Result<()> {
+        let pending = PendingRead {
+            client: request.src.clone(),
+            msg_id: request.body.msg_id,
+            wait: wait.unwrap_or(Wait::Keys(vec![])),
+            deadline: Instant::now() + timeout,
+        };
+        if self.satisfied(link, &pending.wait) {
+            self.answer(link, &pending)
+        } else {
+            self.pending_reads.push(pending);
+            Ok(())
+        }
+    }
+    /// Answer every pending read that is satisfied or out of time
+    pub fn answer_reads(&mut self, link: &mut Link) -> Result<()> {
+        if self.pending_reads.is_empty() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let (ready, waiting) = std::mem::take(&mut self.pending_reads)
+            .into_iter()
+            .partition(|read: &PendingRead| {
+                read.deadline <= now || self.satisfied(link, &read.wait)
+            });
+        self.pending_reads = waiting;
+        for read in ready {
+            self.answer(link, &read)?;
+        }
+        Ok(())
+    }
+    fn satisfied(&self, link: &Link, wait: &Wait) -> bool {
+        match wait {
+            Wait::Keys(keys) => keys.iter().all(|key| self.values.contains(key)),
+            Wait::ConfirmedBefore(cutoff) => {
+                let quorum = reads::quorum(link.neighbors.len());
+                let older = self.arrivals.partition_point(|arrival| arrival <= cutoff);
+                self.values.as_slice()[..older].iter().all(|value| {
+                    let key = value.key();
+                    let confirmations = link
+                        .neighbors
+                        .iter()
+                        .filter(|n| self.known.get(*n).is_some_and(|known| known.contains(&key)))
+                        .count();
+                    confirmations >= quorum
+                })
+            }
+        }
+    }
+    fn answer(&self, link: &mut Link, read: &PendingRead) -> Result<()> {
+        let messages = self.values.as_slice();
+        link.reply(&read.client, read.msg_id, ReadOkRef { messages })
+    }
+    /// A peer forwarded us a batch of values, along with the stamps they had on that peer. A
+    /// retransmission of nothing but duplicates only means our acknowledgement was lost, so it
+    /// does not count against the link.
+    pub fn forwarded(
+        &mut self,
+        link: &mut Link,
+        from: &str,
+        values: Vec<T>,
+        stamps: Vec<Option<Stamp>>,
+        retransmission: bool,
+    ) -> Result<()> {
+        let keys: Vec<u64> = values.iter().map(Payload::key).collect();
+        self.learned_from(from, &keys);
+        let stamps = stamps.into_iter().chain(std::iter::repeat(None));
+        let mut any_new = false;
+        for (value, stamp) in values.into_iter().zip(stamps) {
+            any_new |= self.broadcast(link, value, stamp.map(Stamp::next_hop), Some(from))?;
+        }
+        let redundant = !any_new
+            && !retransmission
+            && self
+                .plumtree
+                .as_mut()
+                .is_some_and(|plumtree| plumtree.duplicates_from(from));
+        if redundant {
+            link.send(from, P::<T>::Prune {})?;
+        }
+        Ok(())
+    }
+    /// A peer acknowledged forwarded values or gossip we sent it
+    pub fn acked(&mut self, msg_ids: &[u64]) {
+        for msg_id in msg_ids {
+            if let Some(unacked) = self.unacked.remove(msg_id) {
+                self.learned_from(&unacked.neighbor, &unacked.keys);
+            }
+            if let Some((neighbor, keys)) = self.pending_fyis.remove(msg_id) {
+                self.learned_from(&neighbor, &keys);
+            }
+        }
+    }
+    /// Acknowledge a message from a peer with the next thing sent to it, or by itself once
+    /// ACK_DELAY is up
+    pub fn owe_ack(&mut self, peer: &str, msg_id: Option<u64>) {
+        if let Some(msg_id) = msg_id {
+            let (_, ids) = self
+                .owed_acks
+                .entry(peer.to_string())
+                .or_insert_with(|| (Instant::now(), vec![]));
+            ids.push(msg_id);
+        }
+    }
+    /// Everything owed to a peer, to send along with a message to it
+    fn take_acks(&mut self, peer: &str) -> Vec<u64> {
+        self.owed_acks.remove(peer).map_or(vec![], |(_, ids)| ids)
+    }
+    /// Send the acknowledgements that have waited out ACK_DELAY without anything to go with
+    pub fn send_acks(&mut self, link: &mut Link) -> Result<()> {
+        let due: Vec<String> = self
+            .owed_acks
+            .iter()
+            .filter(|(_, (since, _))| since.elapsed() >= ACK_DELAY)
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        for peer in due {
+            let ids = self.take_acks(&peer);
+            link.send(&peer, P::<T>::Ack { ids })?;
+        }
+        Ok(())
+    }
+    /// Queue a newly learned value for every neighbor except the one it came from, or only the
+    /// eager ones in plumtree mode
+    fn forward(&mut self, link: &mut Link, key: u64, from: Option<&str>) -> Result<()> {
+        let targets: Vec<String> = match self.plumtree.as_mut() {
+            Some(plumtree) => plumtree.delivered(key, from),
+            None => link
+                .neighbors
+                .iter()
+                .filter(|n| Some(n.as_str()) != from)
+                .cloned()
+                .collect(),
+        };
+        for neighbor in targets {
+            let (_, batch) = self
+                .batches
+                .entry(neighbor.clone())
+                .or_insert_with(|| (Instant::now(), vec![]));
+            batch.push(key);
+            if batch.len() >= link.settings.batch_size || link.settings.batch_window.is_zero() {
+                self.flush_to(link, &neighbor)?;
+            }
+        }
+        Ok(())
+    }
+    /// Send off every batch that has waited out the batch window
+    pub fn flush(&mut self, link: &mut Link) -> Result<()> {
+        let expired: Vec<String> = self
+            .batches
+            .iter()
+            .filter(|(_, (queued_at, _))| queued_at.elapsed() >= link.settings.batch_window)
+            .map(|(neighbor, _)| neighbor.clone())
+            .collect();
+        for neighbor in expired {
+            self.flush_to(link, &neighbor)?;
+        }
+        Ok(())
+    }
+    fn flush_to(&mut self, link: &mut Link, neighbor: &str) -> Result<()> {
+        match self.batches.remove(neighbor) {
+            Some((_, keys)) => self.send_to_peer(link, neighbor, keys, 0),
+            None => Ok(()),
+        }
+    }
+    /// Send values to a neighbor, and keep track of them until the neighbor acknowledges them
+    fn send_to_peer(
+        &mut self,
+        link: &mut Link,
+        neighbor: &str,
+        keys: Vec<u64>,
+        attempts: u32,
+    ) -> Result<()> {
+        let values = self.lookup(&keys);
+        let stamps = keys
+            .iter()
+            .filter(|key| self.values.contains(key))
+            .map(|key| self.propagation.stamp(*key))
+            .collect();
+        let broadcast = P::BroadcastToPeers {
+            values,
+            stamps,
+            retransmission: attempts > 0,
+            acks: self.take_acks(neighbor),
+        };
+        let msg_id = link.send_tracked(neighbor, broadcast)?;
+        self.unacked.insert(
+            msg_id,
+            Unacked {
+                neighbor: neighbor.to_string(),
+                keys,
+                sent_at: Instant::now(),
+                attempts,
+            },
+        );
+        Ok(())
+    }
+    /// Resend everything that has waited out its retry timeout, one message per neighbor
+    pub fn retry(&mut self, link: &mut Link) -> Result<()> {
+        let expired: Vec<u64> = self
+            .unacked
+            .iter()
+            .filter(|(_, unacked)| {
+                unacked.sent_at.elapsed()
+                    >= retry_timeout(link.sender, &unacked.neighbor, unacked.attempts)
+            })
+            .map(|(msg_id, _)| *msg_id)
+            .collect();
+        let mut resends: HashMap<String, (Vec<u64>, u32)> = HashMap::new();
+        for msg_id in expired {
+            let unacked = self.unacked.remove(&msg_id).unwrap();
+            let (keys, attempts) = resends.entry(unacked.neighbor).or_default();
+            keys.extend(unacked.keys);
+            *attempts = (*attempts).max(unacked.attempts + 1);
+        }
+        for (neighbor, (mut keys, attempts)) in resends {
+            keys.sort_unstable();
+            keys.dedup();
+            self.send_to_peer(link, &neighbor, keys, attempts)?;
+        }
+        Ok(())
+    }
+    /// Send plumtree's lazy announcements, and graft in peers for anything the tree has missed
+    pub fn tick_plumtree(&mut self, link: &mut Link) -> Result<()> {
+        let Some(plumtree) = self.plumtree.as_mut() else {
+            return Ok(());
+        };
+        let announcements = plumtree.take_announcements();
+        let grafts = plumtree.expired();
+        for (peer, ids) in announcements {
+            link.send(&peer, P::<T>::IHave { ids })?;
+        }
+        for (peer, ids) in grafts {
+            link.send(&peer, P::<T>::Graft { ids })?;
+        }
+        Ok(())
+    }
+    pub fn announced(&mut self, from: &str, ids: &[u64]) {
+        if let Some(plumtree) = self.plumtree.as_mut() {
+            let values = &self.values;
+            let missing = ids.iter().copied().filter(|id| !values.contains(id));
+            plumtree.announced(missing, from);
+        }
+    }
+    pub fn grafted(&mut self, link: &mut Link, from: &str, ids: &[u64]) -> Result<()> {
+        if let Some(plumtree) = self.plumtree.as_mut() {
+            plumtree.grafted(from);
+        }
+        let keys: Vec<u64> = ids
+            .iter()
+            .copied()
+            .filter(|id| self.values.contains(id))
+            .collect();
+        if keys.is_empty() {
+            return Ok(());
+        }
+        self.send_to_peer(link, from, keys, 0)
+    }
+    pub fn pruned(&mut self, from: &str) {
+        if let Some(plumtree) = self.plumtree.as_mut() {
+            plumtree.pruned(from);
+        }
+    }
+    /// Periodic anti-entropy, in case anything slipped past the forwarding
+    pub fn gossip(&mut self, link: &mut Link) -> Result<()> {
+        for neighbor in link.neighbors {
+            if link.settings.digest_sync {
+                let ranges = vec![self.index.root()];
+                link.send(neighbor, P::<T>::Sync { ranges })?;
+            } else {
+                self.gossip_to(link, neighbor)?;
+            }
+        }
+        Ok(())
+    }
+    /// Send a neighbor only the values it has not yet acknowledged
+    fn gossip_to(&mut self, link: &mut Link, neighbor: &str) -> Result<()> {
+        let known = self.known.entry(neighbor.to_string()).or_default();
+        let delta: Vec<T> = self
+            .values
+            .as_slice()
+            .iter()
+            .filter(|value| !known.contains(&value.key()))
+            .cloned()
+            .collect();
+        // Any previous gossip that was not acknowledged is covered by this one
+        self.pending_fyis.retain(|_, (n, _)| n != neighbor);
+        if delta.is_empty() {
+            return Ok(());
+        }
+        let msg_id = link.send_tracked(
+            neighbor,
+            P::<T>::Fyi {
+                values: T::pack(&delta)?,
+                acks: self.take_acks(neighbor),
+            },
+        )?;
+        let keys = delta.iter().map(Payload::key).collect();
+        self.pending_fyis
+            .insert(msg_id, (neighbor.to_string(), keys));
+        Ok(())
+    }
+    /// Take gossiped values without forwarding them
+    pub fn fyi(&mut self, from: &str, values: Vec<T>) {
+        let keys: Vec<u64> = values.iter().map(Payload::key).collect();
+        for value in values {
+            self.insert(value, None);
+        }
+        self.learned_from(from, &keys);
+    }
+    /// Answer a peer's range digests with whatever each range needs next
+    pub fn sync(&mut self, link: &mut Link, peer: &str, ranges: &[RangeDigest]) -> Result<()> {
+        let mut next_ranges = vec![];
+        let mut shipped_ranges = vec![];
+        let mut shipped_keys = vec![];
+        for range in ranges {
+            match self.index.compare(range) {
+                Step::Match => {}
+                Step::Ship(keys) => {
+                    shipped_ranges.push((range.start, range.end));
+                    shipped_keys.extend(keys);
+                }
+                Step::Ask(ours) => next_ranges.push(ours),
+                Step::Split(subranges) => next_ranges.extend(subranges),
+            }
+        }
+        if !next_ranges.is_empty() {
+            link.send(
+                peer,
+                P::<T>::Sync {
+                    ranges: next_ranges,
+                },
+            )?;
+        }
+        if !shipped_ranges.is_empty() {
+            let values = self.lookup(&shipped_keys);
+            link.send(
+                peer,
+                P::SyncValues {
+                    ranges: shipped_ranges,
+                    values,
+                },
+            )?;
+        }
+        Ok(())
+    }
+    /// Take a peer's values for some ranges, and send back whatever it is missing
+    pub fn sync_values(
+        &mut self,
+        link: &mut Link,
+        peer: &str,
+        ranges: &[(u64, u64)],
+        values: Vec<T>,
+    ) -> Result<()> {
+        let theirs: HashSet<u64> = values.iter().map(Payload::key).collect();
+        for value in values {
+            self.insert(value, None);
+        }
+        let missing: Vec<u64> = ranges
+            .iter()
+            .flat_map(|&(start, end)| self.index.values_in(start, end))
+            .filter(|key| !theirs.contains(key))
+            .collect();
+        if !missing.is_empty() {
+            let values = T::pack(&self.lookup(&missing))?;
+            let acks = self.take_acks(peer);
+            link.send(peer, P::<T>::Fyi { values, acks })?;
+        }
+        Ok(())
+    }
+    /// Follow a change of neighbors. Anyone new gets everything we have that it is not known to
+    /// have straight away, so that nothing is lost while the rest of the cluster is still
+    /// switching over.
+    pub fn set_neighbors(&mut self, link: &mut Link, added: &[String]) -> Result<()> {
+        let neighbors = link.neighbors;
+        self.pending_fyis.retain(|_, (n, _)| neighbors.contains(n));
+        self.batches.retain(|n, _| neighbors.contains(n));
+        self.unacked
+            .retain(|_, unacked| neighbors.contains(&unacked.neighbor));
+        if let Some(plumtree) = self.plumtree.as_mut() {
+            plumtree.set_neighbors(neighbors);
+        }
+        for neighbor in added {
+            self.gossip_to(link, neighbor)?;
+        }
+        Ok(())
+    }
+}
+
+/// How long to wait for an acknowledgement before sending again. Starts at a couple of round
+/// trips, plus however long the neighbor may hold the acknowledgement, and doubles with every
+/// attempt.
+fn retry_timeout(sender: &Sender, neighbor: &str, attempts: u32) -> Duration {
+    let base = sender
+        .latency
+        .peer(neighbor)
+        .and_then(|latency| latency.percentile(0.99))
+        .map_or(DEFAULT_RETRY_TIMEOUT, |rtt| {
+            (rtt * 2).max(MIN_RETRY_TIMEOUT) + ACK_DELAY
+        });
+    (base * 2u32.saturating_pow(attempts)).min(MAX_RETRY_TIMEOUT)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    /// A channel on n0, along with everything a link needs
+    struct Node {
+        sender: Sender,
+        neighbors: Vec<String>,
+        settings: Settings,
+        channel: Channel<u64>,
+    }
+
+    impl Node {
+        fn new(neighbors: &[&str]) -> Node {
+            let settings = Settings {
+                batch_window: Duration::from_secs(60),
+                batch_size: 100,
+                digest_sync: false,
+                graft_timeout: None,
+            };
+            let neighbors: Vec<String> = neighbors.iter().map(|n| n.to_string()).collect();
+            Node {
+                sender: Sender::for_test("n0", &["n0", "n1", "n2"]),
+                channel: Channel::new(&neighbors, &settings),
+                neighbors,
+                settings,
+            }
+        }
+        fn link(&mut self) -> (&mut Channel<u64>, Link<'_>) {
+            let link = Link {
+                sender: &mut self.sender,
+                neighbors: &self.neighbors,
+                settings: &self.settings,
+                topic: None,
+            };
+            (&mut self.channel, link)
+        }
+        fn submit(&mut self, value: u64) {
+            let (channel, mut link) = self.link();
+            channel.submit(&mut link, value).unwrap();
+        }
+        /// Read from c1, and return the values if the read was answered straight away
+        fn read(&mut self, wait: Wait, timeout: Duration) -> Option<Vec<u64>> {
+            let request = Message {
+                src: "c1".to_string(),
+                dest: "n0".to_string(),
+                body: crate::server::Body {
+                    msg_id: Some(1),
+                    in_reply_to: None,
+                    fields: (),
+                },
+            };
+            let (channel, mut link) = self.link();
+            channel
+                .read(&mut link, &request, Some(wait), timeout)
+                .unwrap();
+            self.read_ok()
+        }
+        fn answer_reads(&mut self) -> Option<Vec<u64>> {
+            let (channel, mut link) = self.link();
+            channel.answer_reads(&mut link).unwrap();
+            self.read_ok()
+        }
+        fn read_ok(&mut self) -> Option<Vec<u64>> {
+            let answers = self.sent("read_ok");
+            assert!(answers.len() <= 1);
+            answers.into_iter().next().map(|(dest, _, body)| {
+                assert_eq!(dest, "c1");
+                assert_eq!(body["in_reply_to"], 1);
+                let mut values: Vec<u64> =
+                    serde_json::from_value(body["messages"].clone()).unwrap();
+                values.sort_unstable();
+                values
+            })
+        }
+        fn flush(&mut self) {
+            let (channel, mut link) = self.link();
+            channel.flush(&mut link).unwrap();
+        }
+        /// The values forwarded to each neighbor since the last call, by destination
+        fn forwarded(&mut self) -> HashMap<String, Vec<u64>> {
+            self.sent("broadcast_to_peers")
+                .into_iter()
+                .map(|(dest, _, body)| {
+                    let values = serde_json::from_value(body["messages"].clone()).unwrap();
+                    (dest, values)
+                })
+                .collect()
+        }
+        fn gossip(&mut self) {
+            let (channel, mut link) = self.link();
+            channel.gossip(&mut link).unwrap();
+        }
+        fn set_neighbors(&mut self, neighbors: &[&str], added: &[&str]) {
+            self.neighbors = neighbors.iter().map(|n| n.to_string()).collect();
+            let added: Vec<String> = added.iter().map(|n| n.to_string()).collect();
+            let (channel, mut link) = self.link();
+            channel.set_neighbors(&mut link, &added).unwrap();
+        }
+        /// The messages of one type sent since the last call, as (destination, msg_id, body)
+        fn sent(&mut self, r#type: &str) -> Vec<(String, u64, Value)> {
+            self.sender
+                .take_sent()
+                .into_iter()
+                .filter(|message| message["body"]["type"] == r#type)
+                .map(|message| {
+                    let dest = message["dest"].as_str().unwrap().to_string();
+                    let msg_id = message["body"]["msg_id"].as_u64().unwrap();
+                    (dest, msg_id, message["body"].clone())
+                })
+                .collect()
+        }
+        /// The values gossiped to each neighbor since the last call, by destination
+        fn gossiped(&mut self) -> HashMap<String, (u64, Vec<u64>)> {
+            self.sent("fyi")
+                .into_iter()
+                .map(|(dest, msg_id, body)| {
+                    let values = u64::unpack(body["messages"].clone()).unwrap();
+                    (dest, (msg_id, values))
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn gossip_only_sends_what_each_neighbor_lacks() {
+        let mut node = Node::new(&["n1", "n2"]);
+        node.channel.fyi("n1", vec![1, 2]);
+        node.channel.fyi("n2", vec![3]);
+        node.gossip();
+        let gossiped = node.gossiped();
+        assert_eq!(gossiped["n1"].1, vec![3]);
+        assert_eq!(gossiped["n2"].1, vec![1, 2]);
+    }
+
+    #[test]
+    fn gossip_skips_neighbors_that_have_everything() {
+        let mut node = Node::new(&["n1"]);
+        node.channel.fyi("n1", vec![1, 2]);
+        node.gossip();
+        assert!(node.gossiped().is_empty());
+    }
+
+    #[test]
+    fn acknowledged_gossip_is_not_resent() {
+        let mut node = Node::new(&["n1", "n2"]);
+        node.channel.fyi("n1", vec![1, 2]);
+        node.gossip();
+        let (msg_id, _) = node.gossiped()["n2"];
+        node.channel.acked(&[msg_id]);
+        node.channel.fyi("n3", vec![3]);
+        node.gossip();
+        let gossiped = node.gossiped();
+        assert_eq!(gossiped["n1"].1, vec![3]);
+        assert_eq!(gossiped["n2"].1, vec![3]);
+        // Neither acknowledged that, so both are sent it again
+        node.gossip();
+        let gossiped = node.gossiped();
+        assert_eq!(gossiped["n1"].1, vec![3]);
+        assert_eq!(gossiped["n2"].1, vec![3]);
+    }
+
+    #[test]
+    fn a_reconnecting_neighbor_converges() {
+        let mut node = Node::new(&["n1"]);
+        node.channel.fyi("n1", vec![1]);
+        node.set_neighbors(&[], &[]);
+        node.channel.fyi("n2", vec![2, 3]);
+        node.gossip();
+        assert!(node.gossiped().is_empty());
+        // Coming back, n1 is sent everything it missed straight away
+        node.set_neighbors(&["n1"], &["n1"]);
+        let (msg_id, values) = node.gossiped().remove("n1").unwrap();
+        assert_eq!(values, vec![2, 3]);
+        let mut n1 = Channel::<u64>::new(&[], &node.settings);
+        n1.fyi("n2", vec![1]);
+        n1.fyi("n0", values);
+        let mut all = n1.values.as_slice().to_vec();
+        all.sort_unstable();
+        assert_eq!(all, vec![1, 2, 3]);
+        node.channel.acked(&[msg_id]);
+        node.gossip();
+        assert!(node.gossiped().is_empty());
+    }
+
+    #[test]
+    fn a_removed_neighbor_is_handed_everything_when_it_returns() {
+        let mut node = Node::new(&["n1", "n2"]);
+        node.settings.batch_window = Duration::ZERO;
+        node.submit(1);
+        let forwarded = node.sent("broadcast_to_peers");
+        assert_eq!(forwarded.len(), 2);
+        node.settings.batch_window = Duration::from_secs(60);
+        node.submit(2);
+        node.set_neighbors(&["n1"], &[]);
+        // Nothing is kept around for n2, and a late acknowledgement from it changes nothing
+        assert!(!node.channel.batches.contains_key("n2"));
+        assert!(node.channel.unacked.values().all(|u| u.neighbor == "n1"));
+        for (_, msg_id, _) in &forwarded {
+            node.channel.acked(&[*msg_id]);
+        }
+        assert!(node.channel.known.get("n2").is_none_or(HashSet::is_empty));
+        // n1 still has its batch coming
+        assert_eq!(node.channel.batches["n1"].1, vec![2]);
+        node.set_neighbors(&["n1", "n2"], &["n2"]);
+        assert_eq!(node.gossiped()["n2"].1, vec![1, 2]);
+    }
+
+    #[test]
+    fn batches_wait_out_the_window() {
+        let mut node = Node::new(&["n1", "n2"]);
+        node.submit(1);
+        node.submit(2);
+        node.flush();
+        assert!(node.forwarded().is_empty());
+        node.settings.batch_window = Duration::ZERO;
+        node.flush();
+        let forwarded = node.forwarded();
+        assert_eq!(forwarded["n1"], vec![1, 2]);
+        assert_eq!(forwarded["n2"], vec![1, 2]);
+        // Flushed batches are not sent again
+        node.flush();
+        assert!(node.forwarded().is_empty());
+    }
+
+    #[test]
+    fn full_batches_are_sent_early() {
+        let mut node = Node::new(&["n1"]);
+        node.settings.batch_size = 2;
+        node.submit(1);
+        assert!(node.forwarded().is_empty());
+        node.submit(2);
+        assert_eq!(node.forwarded()["n1"], vec![1, 2]);
+        node.submit(3);
+        assert!(node.forwarded().is_empty());
+    }
+
+    #[test]
+    fn values_are_not_forwarded_back_where_they_came_from() {
+        let mut node = Node::new(&["n1", "n2"]);
+        node.settings.batch_window = Duration::ZERO;
+        let (channel, mut link) = node.link();
+        channel
+            .forwarded(&mut link, "n1", vec![1], vec![], false)
+            .unwrap();
+        let forwarded = node.forwarded();
+        assert!(!forwarded.contains_key("n1"));
+        assert_eq!(forwarded["n2"], vec![1]);
+    }
+
+    #[test]
+    fn owed_acks_ride_along_with_forwarded_values() {
+        let mut node = Node::new(&["n1", "n2"]);
+        node.settings.batch_window = Duration::ZERO;
+        node.channel.owe_ack("n1", Some(7));
+        node.submit(1);
+        let sent = node.sent("broadcast_to_peers");
+        let acks = |dest: &str| {
+            let (_, _, body) = sent.iter().find(|(d, _, _)| d == dest).unwrap();
+            body.get("acks").cloned()
+        };
+        assert_eq!(acks("n1"), Some(serde_json::json!([7])));
+        assert_eq!(acks("n2"), None);
+        node.channel.owed_acks.clear();
+        let (channel, mut link) = node.link();
+        channel.send_acks(&mut link).unwrap();
+        assert!(node.sent("ack").is_empty());
+    }
+
+    #[test]
+    fn acks_with_nothing_to_ride_along_with_wait_out_the_delay() {
+        let mut node = Node::new(&["n1"]);
+        node.channel.owe_ack("n1", Some(7));
+        node.channel.owe_ack("n1", Some(8));
+        node.channel.owe_ack("n1", None);
+        let (channel, mut link) = node.link();
+        channel.send_acks(&mut link).unwrap();
+        assert!(node.sent("ack").is_empty());
+        node.channel.owed_acks.get_mut("n1").unwrap().0 -= ACK_DELAY;
+        let (channel, mut link) = node.link();
+        channel.send_acks(&mut link).unwrap();
+        let acks = node.sent("ack");
+        assert_eq!(acks.len(), 1);
+        assert_eq!(acks[0].2["ids"], serde_json::json!([7, 8]));
+        assert!(node.channel.owed_acks.is_empty());
+    }
+
+    #[test]
+    fn acknowledged_values_are_not_retried() {
+        let mut node = Node::new(&["n1", "n2"]);
+        node.settings.batch_window = Duration::ZERO;
+        node.submit(1);
+        let sent = node.sent("broadcast_to_peers");
+        let (_, msg_id, _) = sent.iter().find(|(dest, _, _)| dest == "n1").unwrap();
+        node.channel.acked(&[*msg_id]);
+        assert!(node.channel.unacked.values().all(|u| u.neighbor == "n2"));
+        // Acknowledging twice, or something unknown, is harmless
+        node.channel.acked(&[*msg_id, 12345]);
+        assert_eq!(node.channel.unacked.len(), 1);
+        node.gossip();
+        let gossiped = node.gossiped();
+        assert!(!gossiped.contains_key("n1"));
+        assert_eq!(gossiped["n2"].1, vec![1]);
+    }
+
+    #[test]
+    fn retry_timeouts_back_off_from_the_round_trip() {
+        let mut sender = Sender::for_test("n0", &["n0", "n1", "n2"]);
+        assert_eq!(retry_timeout(&sender, "n1", 0), DEFAULT_RETRY_TIMEOUT);
+        assert_eq!(retry_timeout(&sender, "n1", 1), DEFAULT_RETRY_TIMEOUT * 2);
+        assert_eq!(retry_timeout(&sender, "n1", 10), MAX_RETRY_TIMEOUT);
+        assert_eq!(retry_timeout(&sender, "n1", u32::MAX), MAX_RETRY_TIMEOUT);
+        sender.latency.record("n1", Duration::from_millis(10));
+        assert_eq!(
+            retry_timeout(&sender, "n1", 0),
+            MIN_RETRY_TIMEOUT + ACK_DELAY
+        );
+        sender.latency.record("n2", Duration::from_millis(300));
+        let base = Duration::from_millis(600) + ACK_DELAY;
+        assert_eq!(retry_timeout(&sender, "n2", 0), base);
+        assert_eq!(retry_timeout(&sender, "n2", 2), base * 4);
+    }
+
+    const LONG: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn local_reads_are_answered_straight_away() {
+        let mut node = Node::new(&["n1"]);
+        node.submit(1);
+        assert_eq!(node.read(Wait::Keys(vec![]), LONG), Some(vec![1]));
+    }
+
+    #[test]
+    fn read_your_writes_waits_for_writes_from_elsewhere() {
+        let mut node = Node::new(&["n1"]);
+        node.submit(1);
+        assert_eq!(node.read(Wait::written(&[1_u64]), LONG), Some(vec![1]));
+        assert_eq!(node.read(Wait::written(&[1_u64, 2]), LONG), None);
+        assert_eq!(node.answer_reads(), None);
+        node.channel.fyi("n1", vec![2]);
+        assert_eq!(node.answer_reads(), Some(vec![1, 2]));
+        assert!(node.channel.pending_reads.is_empty());
+    }
+
+    #[test]
+    fn waiting_reads_are_answered_at_the_deadline() {
+        let mut node = Node::new(&["n1"]);
+        node.submit(1);
+        assert_eq!(node.read(Wait::written(&[2_u64]), Duration::ZERO), None);
+        assert_eq!(node.answer_reads(), Some(vec![1]));
+    }
+
+    #[test]
+    fn bounded_staleness_waits_for_a_quorum_to_confirm() {
+        let mut node = Node::new(&["n1", "n2", "n3"]);
+        node.submit(1);
+        node.channel.fyi("n1", vec![2]);
+        let cutoff = Instant::now();
+        node.submit(3);
+        let wait = || Wait::ConfirmedBefore(cutoff);
+        assert_eq!(node.read(wait(), LONG), None);
+        // 1 and 2 arrived before the cutoff and need two confirmations each, 3 does not count
+        node.channel.fyi("n2", vec![1, 2]);
+        assert_eq!(node.answer_reads(), None);
+        node.channel.fyi("n3", vec![1]);
+        assert_eq!(node.answer_reads(), Some(vec![1, 2, 3]));
+        assert_eq!(node.read(wait(), LONG), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn bounded_staleness_without_neighbors_is_always_confirmed() {
+        let mut node = Node::new(&[]);
+        node.submit(1);
+        let cutoff = Instant::now();
+        assert_eq!(
+            node.read(Wait::ConfirmedBefore(cutoff), LONG),
+            Some(vec![1])
+        );
+    }
+}
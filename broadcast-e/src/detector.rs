@@ -0,0 +1,205 @@
+//! The phi accrual failure detector (Hayashibara et al., 2004).
+//!
+//! Rather than declaring a peer dead after a fixed timeout, each peer's heartbeat inter-arrival
+//! times are modelled as a normal distribution, and phi is how unlikely it is that a heartbeat
+//! would still be outstanding after the time since the last one: phi = -log10(P(later)). A phi
+//! of 8 means the wait had roughly a one in a hundred million chance under normal conditions. The
+//! threshold adapts to each link's own jitter, so slow but steady peers are not suspected.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Phi above which a peer is suspected
+pub const DEFAULT_THRESHOLD: f64 = 8.0;
+/// Number of recent inter-arrival times kept per peer
+const WINDOW: usize = 100;
+/// Floor on the standard deviation, so that perfectly regular heartbeats do not make a peer
+/// suspect the moment one is slightly late
+const MIN_STD_DEV: Duration = Duration::from_millis(100);
+
+/// A peer changed state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Suspected(String),
+    Recovered(String),
+}
+
+struct Heartbeats {
+    last: Instant,
+    intervals: VecDeque<f64>,
+}
+
+impl Heartbeats {
+    /// Start out as if a heartbeat just arrived and they arrive every `expected` interval, so
+    /// that a peer that never answers at all is eventually suspected too
+    fn new(expected: Duration) -> Heartbeats {
+        let expected = expected.as_secs_f64();
+        Heartbeats {
+            last: Instant::now(),
+            intervals: VecDeque::from([expected - expected / 4.0, expected + expected / 4.0]),
+        }
+    }
+    fn record(&mut self) {
+        if self.intervals.len() == WINDOW {
+            self.intervals.pop_front();
+        }
+        self.intervals.push_back(self.last.elapsed().as_secs_f64());
+        self.last = Instant::now();
+    }
+    fn phi(&self) -> f64 {
+        self.phi_after(self.last.elapsed().as_secs_f64())
+    }
+    /// Phi once `waited` seconds have passed since the last heartbeat
+    fn phi_after(&self, waited: f64) -> f64 {
+        let n = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<f64>() / n;
+        let variance = self
+            .intervals
+            .iter()
+            .map(|i| (i - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        let std_dev = variance.sqrt().max(MIN_STD_DEV.as_secs_f64());
+        // Logistic approximation of the normal CDF, as used by Akka and Cassandra
+        let y = (waited - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        let p_later = if y > 0.0 {
+            e / (1.0 + e)
+        } else {
+            1.0 - 1.0 / (1.0 + e)
+        };
+        -p_later.max(f64::MIN_POSITIVE).log10()
+    }
+}
+
+/// A suspected or alive view of every peer
+pub struct FailureDetector {
+    peers: HashMap<String, Heartbeats>,
+    suspected: HashSet<String>,
+    /// How often watched peers are expected to send a heartbeat
+    interval: Duration,
+    pub threshold: f64,
+}
+
+impl FailureDetector {
+    /// A detector watching nobody yet, for peers that send a heartbeat every `interval`
+    pub fn new(interval: Duration) -> Self {
+        FailureDetector {
+            peers: HashMap::new(),
+            suspected: HashSet::new(),
+            interval,
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+    /// Watch exactly `peers`. Peers that were already watched keep their history, new ones
+    /// start out as if they had just sent a heartbeat, and the rest are forgotten.
+    pub fn watch(&mut self, peers: &[String]) {
+        self.peers.retain(|peer, _| peers.contains(peer));
+        self.suspected.retain(|peer| peers.contains(peer));
+        for peer in peers {
+            if !self.peers.contains_key(peer) {
+                self.peers
+                    .insert(peer.clone(), Heartbeats::new(self.interval));
+            }
+        }
+    }
+    pub fn heartbeat(&mut self, peer: &str) {
+        if let Some(heartbeats) = self.peers.get_mut(peer) {
+            heartbeats.record();
+        }
+    }
+    pub fn phi(&self) -> HashMap<String, f64> {
+        self.peers
+            .iter()
+            .map(|(peer, heartbeats)| (peer.clone(), heartbeats.phi()))
+            .collect()
+    }
+    pub fn is_suspected(&self, peer: &str) -> bool {
+        self.suspected.contains(peer)
+    }
+    pub fn suspected(&self) -> Vec<String> {
+        let mut suspected: Vec<String> = self.suspected.iter().cloned().collect();
+        suspected.sort();
+        suspected
+    }
+    /// Re-evaluate every peer, returning the ones that changed state since the last check
+    pub fn check(&mut self) -> Vec<Change> {
+        let mut changes = vec![];
+        for (peer, heartbeats) in &self.peers {
+            let suspect = heartbeats.phi() > self.threshold;
+            if suspect && self.suspected.insert(peer.clone()) {
+                changes.push(Change::Suspected(peer.clone()));
+            } else if !suspect && self.suspected.remove(peer) {
+                changes.push(Change::Recovered(peer.clone()));
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeats(intervals: &[f64]) -> Heartbeats {
+        Heartbeats {
+            last: Instant::now(),
+            intervals: intervals.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn phi_grows_with_the_wait() {
+        let regular = heartbeats(&[1.0; 10]);
+        let phis: Vec<f64> = [0.5, 1.0, 1.2, 1.4, 1.6]
+            .iter()
+            .map(|&waited| regular.phi_after(waited))
+            .collect();
+        assert!(phis.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", phis);
+        // Half of all heartbeats come later than the mean
+        assert!((phis[1] - 0.5_f64.log10().abs()).abs() < 0.01, "{:?}", phis);
+        assert!(phis[4] > DEFAULT_THRESHOLD, "{:?}", phis);
+    }
+
+    #[test]
+    fn jitter_raises_the_threshold_wait() {
+        let regular = heartbeats(&[1.0; 10]);
+        let jittery = heartbeats(&[0.5, 1.5, 0.5, 1.5, 0.5, 1.5, 0.5, 1.5, 0.5, 1.5]);
+        assert!(jittery.phi_after(1.6) < regular.phi_after(1.6));
+        assert!(jittery.phi_after(1.6) < DEFAULT_THRESHOLD);
+    }
+
+    #[test]
+    fn regular_heartbeats_use_the_floor_on_deviation() {
+        // With no floor, being 10ms late after perfectly regular heartbeats would be infinitely
+        // unlikely
+        let regular = heartbeats(&[1.0; 10]);
+        assert!(regular.phi_after(1.01) < 1.0);
+    }
+
+    #[test]
+    fn check_reports_each_change_once() {
+        let mut detector = FailureDetector::new(Duration::from_secs(1));
+        detector.watch(&["n1".to_string()]);
+        detector.threshold = -1.0;
+        assert_eq!(detector.check(), vec![Change::Suspected("n1".into())]);
+        assert_eq!(detector.check(), vec![]);
+        assert!(detector.is_suspected("n1"));
+        detector.threshold = f64::INFINITY;
+        assert_eq!(detector.check(), vec![Change::Recovered("n1".into())]);
+        assert_eq!(detector.check(), vec![]);
+        assert_eq!(detector.suspected(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn unwatched_peers_are_forgotten() {
+        let mut detector = FailureDetector::new(Duration::from_secs(1));
+        detector.watch(&["n1".to_string(), "n2".to_string()]);
+        detector.threshold = -1.0;
+        detector.check();
+        detector.watch(&["n2".to_string(), "n3".to_string()]);
+        assert_eq!(detector.suspected(), vec!["n2".to_string()]);
+        let mut watched: Vec<String> = detector.phi().into_keys().collect();
+        watched.sort();
+        assert_eq!(watched, vec!["n2".to_string(), "n3".to_string()]);
+    }
+}
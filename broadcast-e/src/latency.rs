@@ -0,0 +1,131 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Weight given to each new sample in the moving average, the same as TCP's SRTT
+const ALPHA: f64 = 0.125;
+/// Number of recent samples kept around for percentiles
+const WINDOW: usize = 100;
+
+/// Round trip times observed for a single peer
+#[derive(Default)]
+pub struct PeerLatency {
+    ewma: Option<Duration>,
+    samples: VecDeque<Duration>,
+}
+
+impl PeerLatency {
+    fn record(&mut self, rtt: Duration) {
+        self.ewma = Some(match self.ewma {
+            Some(ewma) => ewma.mul_f64(1.0 - ALPHA) + rtt.mul_f64(ALPHA),
+            None => rtt,
+        });
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+    }
+    /// The exponentially weighted moving average round trip time
+    pub fn ewma(&self) -> Option<Duration> {
+        self.ewma
+    }
+    /// The round trip time below which `p` (0.0 to 1.0) of the recent samples fall
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<Duration> = self.samples.iter().copied().collect();
+        sorted.sort();
+        let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+        Some(sorted[index])
+    }
+}
+
+/// A serializable snapshot of a peer's round trip times, in milliseconds
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LatencySummary {
+    pub ewma: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+/// Round trip times to every peer that has answered a ping
+#[derive(Default)]
+pub struct Latency {
+    peers: HashMap<String, PeerLatency>,
+}
+
+impl Latency {
+    pub fn record(&mut self, peer: &str, rtt: Duration) {
+        self.peers.entry(peer.to_string()).or_default().record(rtt);
+    }
+    pub fn peer(&self, peer: &str) -> Option<&PeerLatency> {
+        self.peers.get(peer)
+    }
+    pub fn summary(&self) -> HashMap<String, LatencySummary> {
+        let millis = |d: Option<Duration>| d.unwrap_or_default().as_secs_f64() * 1000.0;
+        self.peers
+            .iter()
+            .map(|(peer, latency)| {
+                (
+                    peer.clone(),
+                    LatencySummary {
+                        ewma: millis(latency.ewma()),
+                        p50: millis(latency.percentile(0.5)),
+                        p90: millis(latency.percentile(0.9)),
+                        p99: millis(latency.percentile(0.99)),
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn no_samples() {
+        let latency = PeerLatency::default();
+        assert_eq!(latency.ewma(), None);
+        assert_eq!(latency.percentile(0.5), None);
+    }
+
+    #[test]
+    fn ewma_starts_at_the_first_sample_and_moves_by_alpha() {
+        let mut latency = PeerLatency::default();
+        latency.record(ms(100));
+        assert_eq!(latency.ewma(), Some(ms(100)));
+        latency.record(ms(900));
+        assert_eq!(latency.ewma(), Some(ms(200)));
+    }
+
+    #[test]
+    fn percentiles_round_to_the_nearest_sample() {
+        let mut latency = PeerLatency::default();
+        for millis in (1..=11).rev() {
+            latency.record(ms(millis));
+        }
+        assert_eq!(latency.percentile(0.0), Some(ms(1)));
+        assert_eq!(latency.percentile(0.5), Some(ms(6)));
+        assert_eq!(latency.percentile(0.9), Some(ms(10)));
+        assert_eq!(latency.percentile(1.0), Some(ms(11)));
+    }
+
+    #[test]
+    fn percentiles_only_cover_the_window() {
+        let mut latency = PeerLatency::default();
+        latency.record(ms(1000));
+        for _ in 0..WINDOW {
+            latency.record(ms(10));
+        }
+        assert_eq!(latency.percentile(1.0), Some(ms(10)));
+    }
+}
@@ -1,33 +1,106 @@
 use std::collections::HashMap;
 use std::ops::DerefMut;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use channel::{Channel, Link, Settings};
+use detector::FailureDetector;
+use payload::Payload;
+use propagation::{PropagationSummary, Stamp};
+use reads::{ReadMode, Wait, DEFAULT_READ_TIMEOUT};
 use serde::{Deserialize, Serialize};
-use server::{Message, Sender};
+use server::{Incoming, Message, Sender, PROBE_INTERVAL};
+use topology::Topology;
 
+mod channel;
+mod detector;
+mod latency;
+mod payload;
+mod plumtree;
+mod propagation;
+mod reads;
+mod reconcile;
 mod server;
+mod topology;
+mod values;
 
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum P {
+pub enum P<T> {
     Broadcast {
         #[serde(rename = "message")]
-        value: u64,
+        value: T,
     },
     BroadcastOk {},
     BroadcastToPeers {
-        #[serde(rename = "message")]
-        value: u64,
+        #[serde(rename = "messages")]
+        values: Vec<T>,
+        /// Where each value came from, see propagation::Stamp
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        stamps: Vec<Option<Stamp>>,
+        /// Set when resending values whose acknowledgement was lost, which the peer is likely to
+        /// have already
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        retransmission: bool,
+        /// msg_ids of BroadcastToPeers and Fyi messages the sender acknowledges
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        acks: Vec<u64>,
+    },
+    Read {
+        #[serde(default)]
+        mode: ReadMode,
+        /// For read-your-writes, values the client wrote through other nodes
+        #[serde(rename = "messages", skip_serializing_if = "Option::is_none")]
+        values: Option<Vec<T>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_staleness_ms: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout_ms: Option<u64>,
     },
-    Read {},
     ReadOk {
         #[serde(rename = "messages")]
-        values: Vec<u64>,
+        values: Vec<T>,
     },
     Fyi {
+        /// The gossiped values, compressed with Payload::pack
+        #[serde(rename = "messages")]
+        values: serde_json::Value,
+        /// msg_ids of BroadcastToPeers and Fyi messages the sender acknowledges
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        acks: Vec<u64>,
+    },
+    /// Acknowledgements that had nothing else to go along with, see Channel::send_acks
+    Ack {
+        ids: Vec<u64>,
+    },
+    /// Digest anti-entropy: compare these ranges, see reconcile::Index::compare
+    Sync {
+        ranges: Vec<reconcile::RangeDigest>,
+    },
+    /// Digest anti-entropy: every value we have in these ranges
+    SyncValues {
+        ranges: Vec<(u64, u64)>,
         #[serde(rename = "messages")]
-        values: Vec<u64>,
+        values: Vec<T>,
+    },
+    /// Plumtree: announce ids without their values
+    #[serde(rename = "ihave")]
+    IHave {
+        ids: Vec<u64>,
+    },
+    /// Plumtree: ask a peer to push to us eagerly, starting with these ids
+    Graft {
+        ids: Vec<u64>,
+    },
+    /// Plumtree: ask a peer to stop pushing to us eagerly
+    Prune {},
+    /// How long values took to reach this node
+    PropagationStats {},
+    PropagationStatsOk {
+        #[serde(flatten)]
+        stats: PropagationSummary,
     },
     Topology {
         topology: HashMap<String, Vec<String>>,
@@ -35,104 +108,310 @@ enum P {
     TopologyOk {},
 }
 
-type Context = (Sender, Vec<u64>);
-/// Determine neighbors to ensure we can reach any other node in the network in two hops
-fn sane_neighbors(sender: &Sender) -> Vec<String> {
-    let mut neighbors = vec![];
-    let my_index = sender
-        .node_ids
-        .iter()
-        .position(|n| n == &sender.node_id)
-        .expect("node_id was not in the node_ids list");
-    // let mut pow_two = 1;
-    // while pow_two < sender.node_ids.len() / 8 {
-    //     neighbors.push(sender.node_ids[(my_index + pow_two) % sender.node_ids.len()].to_string());
-    //     pow_two *= 2;
-    // }
-    neighbors.push(sender.node_ids[(my_index + 1) % sender.node_ids.len()].to_string());
-    if my_index % 2 == 0 {
-        neighbors.push(sender.node_ids[(my_index + 4) % sender.node_ids.len()].to_string());
+/// Any message body, on an optional topic. Messages without a topic all share one channel,
+/// which is what plain Maelstrom clients use.
+#[derive(Serialize, Deserialize)]
+pub struct Topical<F> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(flatten)]
+    pub inner: F,
+}
+
+struct Context<T> {
+    sender: Sender,
+    topology: Box<dyn Topology>,
+    neighbors: Vec<String>,
+    settings: Settings,
+    channels: HashMap<Option<String>, Channel<T>>,
+}
+
+impl<T: Payload> Context<T> {
+    /// Get a topic's channel, creating it if this is the first we have heard of it, along with a
+    /// link to send through
+    fn channel(&mut self, topic: &Option<String>) -> (&mut Channel<T>, Link<'_>) {
+        let channel = self
+            .channels
+            .entry(topic.clone())
+            .or_insert_with(|| Channel::new(&self.neighbors, &self.settings));
+        let link = Link {
+            sender: &mut self.sender,
+            neighbors: &self.neighbors,
+            settings: &self.settings,
+            topic: topic.clone(),
+        };
+        (channel, link)
+    }
+    /// Run something on every channel
+    fn each_channel<F>(&mut self, mut f: F) -> serde_json::Result<()>
+    where
+        F: FnMut(&mut Channel<T>, &mut Link) -> serde_json::Result<()>,
+    {
+        let topics: Vec<Option<String>> = self.channels.keys().cloned().collect();
+        for topic in topics {
+            let (channel, mut link) = self.channel(&topic);
+            f(channel, &mut link)?;
+        }
+        Ok(())
     }
-    neighbors
+    /// The topology's neighbors, except that suspected neighbors are replaced by their own
+    /// neighbors, so that values still reach the rest of the cluster around them
+    fn routes(&self) -> Vec<String> {
+        let Sender {
+            node_id,
+            node_ids,
+            detector,
+            ..
+        } = &self.sender;
+        let mut routes: Vec<String> = vec![];
+        for neighbor in self.topology.neighbors(node_id, node_ids) {
+            let detour = if detector.is_suspected(&neighbor) {
+                self.topology.neighbors(&neighbor, node_ids)
+            } else {
+                vec![neighbor]
+            };
+            for node in detour {
+                if &node != node_id && !detector.is_suspected(&node) && !routes.contains(&node) {
+                    routes.push(node);
+                }
+            }
+        }
+        routes
+    }
+    /// The nodes worth probing: the topology's neighbors, whether or not they are suspected, so
+    /// that their recovery is noticed, and any nodes currently routed through in their place
+    fn probe_targets(&self) -> Vec<String> {
+        let mut targets = self
+            .topology
+            .neighbors(&self.sender.node_id, &self.sender.node_ids);
+        for neighbor in &self.neighbors {
+            if !targets.contains(neighbor) {
+                targets.push(neighbor.clone());
+            }
+        }
+        targets
+    }
+    /// Switch to a new set of neighbors
+    fn set_neighbors(&mut self, neighbors: Vec<String>) -> serde_json::Result<()> {
+        let added: Vec<String> = neighbors
+            .iter()
+            .filter(|n| !self.neighbors.contains(n))
+            .cloned()
+            .collect();
+        self.neighbors = neighbors;
+        self.each_channel(|channel, link| channel.set_neighbors(link, &added))
+    }
+}
+
+/// Environment variable selecting the topology::from_config strategy, Maelstrom's own by default
+const TOPOLOGY_VAR: &str = "BROADCAST_TOPOLOGY";
+/// Environment variable for how many milliseconds to hold new values before forwarding them.
+/// Longer windows send fewer messages at the cost of latency; 0 forwards immediately.
+const BATCH_WINDOW_VAR: &str = "BROADCAST_BATCH_MS";
+const DEFAULT_BATCH_WINDOW_MS: u64 = 25;
+/// Environment variable for how many values a batch may hold before it is sent early
+const BATCH_SIZE_VAR: &str = "BROADCAST_BATCH_SIZE";
+const DEFAULT_BATCH_SIZE: usize = 100;
+/// How often to check for unacknowledged values due a retry, acknowledgements that cannot wait
+/// any longer and pending reads that can be answered
+const RETRY_TICK: Duration = Duration::from_millis(10);
+/// Environment variable selecting periodic anti-entropy: "delta" (the default) sends each
+/// neighbor the values it has not acknowledged, "digest" reconciles by comparing range digests
+const ANTI_ENTROPY_VAR: &str = "BROADCAST_ANTI_ENTROPY";
+/// Environment variable selecting how values spread: "flood" (the default) or "plumtree"
+const MODE_VAR: &str = "BROADCAST_MODE";
+/// Environment variable for how many milliseconds plumtree waits for an announced value before
+/// grafting the announcer into the tree
+const GRAFT_TIMEOUT_VAR: &str = "BROADCAST_GRAFT_MS";
+const DEFAULT_GRAFT_TIMEOUT_MS: u64 = 1000;
+/// Environment variable selecting the payload type: "int" (the default) for Maelstrom's integers,
+/// or "json" for arbitrary JSON
+const PAYLOAD_VAR: &str = "BROADCAST_PAYLOAD";
+/// Environment variable for how many milliseconds apart neighbors are pinged. 0 turns probing,
+/// and with it the failure detector, off.
+const PROBE_INTERVAL_VAR: &str = "BROADCAST_PROBE_MS";
+/// Environment variable overriding the phi above which the failure detector suspects a peer
+const PHI_THRESHOLD_VAR: &str = "BROADCAST_PHI_THRESHOLD";
+
+fn env_or<T: FromStr>(var: &str, default: T) -> T {
+    std::env::var(var)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
 }
 
 fn main() -> serde_json::Result<()> {
-    let (server, sender) = server::init()?;
-    let neighbors = sane_neighbors(&sender);
-    let thread_neighbors = neighbors.clone();
-    let context: Arc<Mutex<Context>> = Arc::new(Mutex::new((sender, vec![])));
+    match std::env::var(PAYLOAD_VAR).as_deref() {
+        Ok("json") => run::<serde_json::Value>(),
+        _ => run::<u64>(),
+    }
+}
+
+fn run<T: Payload>() -> serde_json::Result<()> {
+    let (server, mut sender) = server::init()?;
+    let probe_interval = Duration::from_millis(env_or(
+        PROBE_INTERVAL_VAR,
+        PROBE_INTERVAL.as_millis() as u64,
+    ));
+    sender.detector = FailureDetector::new(probe_interval);
+    sender.detector.threshold = env_or(PHI_THRESHOLD_VAR, detector::DEFAULT_THRESHOLD);
+    let topology = topology::from_config(&std::env::var(TOPOLOGY_VAR).unwrap_or_default());
+    let neighbors = topology.neighbors(&sender.node_id, &sender.node_ids);
+    let graft_timeout = Duration::from_millis(env_or(GRAFT_TIMEOUT_VAR, DEFAULT_GRAFT_TIMEOUT_MS));
+    let settings = Settings {
+        batch_window: Duration::from_millis(env_or(BATCH_WINDOW_VAR, DEFAULT_BATCH_WINDOW_MS)),
+        batch_size: env_or(BATCH_SIZE_VAR, DEFAULT_BATCH_SIZE),
+        digest_sync: std::env::var(ANTI_ENTROPY_VAR).as_deref() == Ok("digest"),
+        graft_timeout: (std::env::var(MODE_VAR).as_deref() == Ok("plumtree"))
+            .then_some(graft_timeout),
+    };
+    let context: Arc<Mutex<Context<T>>> = Arc::new(Mutex::new(Context {
+        sender,
+        topology,
+        neighbors,
+        settings,
+        channels: HashMap::new(),
+    }));
+    if !probe_interval.is_zero() {
+        let probe_context = context.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(probe_interval);
+            let mut ctx = probe_context.lock().unwrap();
+            let targets = ctx.probe_targets();
+            let changes = ctx.sender.probe(&targets).expect("Error sending pings");
+            if !changes.is_empty() {
+                let routes = ctx.routes();
+                ctx.set_neighbors(routes).expect("Error rerouting");
+            }
+        });
+    }
+    // Without a window, values are sent as soon as they are queued and there is nothing to flush
+    let window = context.lock().unwrap().settings.batch_window;
+    if !window.is_zero() {
+        let flush_context = context.clone();
+        std::thread::spawn(move || loop {
+            // Wake up often enough that no batch waits much longer than the window
+            std::thread::sleep((window / 4).max(Duration::from_millis(1)));
+            let mut ctx = flush_context.lock().unwrap();
+            ctx.each_channel(|channel, link| channel.flush(link))
+                .expect("Error flushing batches");
+        });
+    }
+    let retry_context = context.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(RETRY_TICK);
+        let mut ctx = retry_context.lock().unwrap();
+        ctx.each_channel(|channel, link| {
+            channel.retry(link)?;
+            channel.send_acks(link)?;
+            channel.answer_reads(link)
+        })
+        .expect("Error sending retries");
+    });
+    // Outside plumtree mode there are no announcements or grafts to send
+    let graft_timeout = context.lock().unwrap().settings.graft_timeout;
+    if let Some(graft_timeout) = graft_timeout {
+        let plumtree_context = context.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(graft_timeout / 2);
+            let mut ctx = plumtree_context.lock().unwrap();
+            ctx.each_channel(|channel, link| channel.tick_plumtree(link))
+                .expect("Error sending plumtree control messages");
+        });
+    }
     let thread_context = context.clone();
     std::thread::spawn(move || loop {
-        std::thread::sleep(std::time::Duration::from_secs(5));
+        std::thread::sleep(Duration::from_secs(5));
         let mut ctx = thread_context.lock().unwrap();
-        let (sender, values) = ctx.deref_mut();
-        for neighbor in thread_neighbors.iter() {
-            sender
-                .send(
-                    neighbor,
-                    &P::Fyi {
-                        values: values.clone(),
-                    },
-                )
-                .expect("Error sending refresh");
-        }
+        ctx.each_channel(|channel, link| channel.gossip(link))
+            .expect("Error sending refresh");
     });
     loop {
-        let message: Message<P> = server.read_message()?;
+        let incoming = server.read_incoming()?;
         let mut ctx = context.lock().unwrap();
-        let (sender, values) = ctx.deref_mut();
-        match message.body.fields {
-            P::Broadcast { value } => {
-                if !values.contains(&value) {
-                    values.push(value);
-                    for neighbor in neighbors.iter().filter(|n| n != &&message.src) {
-                        sender.send(neighbor, &P::BroadcastToPeers { value })?;
-                    }
-                }
-                sender.respond(&message, &P::BroadcastOk {})?
+        let ctx = ctx.deref_mut();
+        let message: Message<Topical<P<T>>> = match incoming {
+            Incoming::Runtime(message) => {
+                ctx.sender.handle_runtime(&message)?;
+                continue;
             }
-            P::BroadcastOk {} => {}
-            P::BroadcastToPeers { value } => {
-                if !values.contains(&value) {
-                    values.push(value);
-                    for neighbor in neighbors.iter().filter(|n| n != &&message.src) {
-                        sender.send(neighbor, &P::BroadcastToPeers { value })?;
-                    }
-                }
-            }
-            P::Read {} => sender.respond(
+            Incoming::Workload(message) => message,
+        };
+        let (message, Topical { topic, inner }) = message.split();
+        let Message { src, body, .. } = &message;
+        let respond = |sender: &mut Sender, fields: P<T>| {
+            let topic = topic.clone();
+            sender.respond(
                 &message,
-                &P::ReadOk {
-                    values: values.clone(),
+                Topical {
+                    topic,
+                    inner: fields,
                 },
-            )?,
-            P::ReadOk {
-                values: read_values,
+            )
+        };
+        let (channel, mut link) = ctx.channel(&topic);
+        let link = &mut link;
+        match inner {
+            P::Broadcast { value } => {
+                channel.submit(link, value)?;
+                respond(link.sender, P::BroadcastOk {})?
+            }
+            P::BroadcastOk {} => {}
+            P::BroadcastToPeers {
+                values,
+                stamps,
+                retransmission,
+                acks,
             } => {
-                for value in read_values {
-                    if !values.contains(&value) {
-                        values.push(value);
-                    }
-                }
+                channel.acked(&acks);
+                channel.owe_ack(src, body.msg_id);
+                channel.forwarded(link, src, values, stamps, retransmission)?;
             }
-            P::Fyi {
-                values: read_values,
+            P::Ack { ids } => channel.acked(&ids),
+            P::IHave { ids } => channel.announced(src, &ids),
+            P::Graft { ids } => channel.grafted(link, src, &ids)?,
+            P::Prune {} => channel.pruned(src),
+            P::Sync { ranges } => channel.sync(link, src, &ranges)?,
+            P::SyncValues { ranges, values } => channel.sync_values(link, src, &ranges, values)?,
+            P::Read {
+                mode,
+                values,
+                max_staleness_ms,
+                timeout_ms,
             } => {
-                for value in read_values {
-                    if !values.contains(&value) {
-                        values.push(value);
+                let wait = match mode {
+                    ReadMode::Local => None,
+                    ReadMode::ReadYourWrites => Some(Wait::written(&values.unwrap_or_default())),
+                    // A bound reaching back before the clock started has no cutoff to wait for
+                    ReadMode::BoundedStaleness => Instant::now()
+                        .checked_sub(Duration::from_millis(max_staleness_ms.unwrap_or(0)))
+                        .map(Wait::ConfirmedBefore),
+                };
+                let timeout = timeout_ms.map_or(DEFAULT_READ_TIMEOUT, Duration::from_millis);
+                channel.read(link, &message, wait, timeout)?;
+            }
+            P::ReadOk { values } => channel.fyi(src, values),
+            // Malformed gossip is dropped unacknowledged rather than taking the node down
+            P::Fyi { values, acks } => {
+                channel.acked(&acks);
+                match T::unpack(values) {
+                    Ok(values) => {
+                        channel.fyi(src, values);
+                        channel.owe_ack(src, body.msg_id);
                     }
+                    Err(err) => eprintln!("Dropping malformed gossip from {}: {}", src, err),
                 }
             }
-            P::Topology { .. } => {
-                // let new_neighbors = topology
-                //     .get(&sender.node_id)
-                //     .expect("This node is not in the topology");
-                // neighbors.clear();
-                // for neighbor in new_neighbors {
-                //     neighbors.push(neighbor.to_string());
-                // }
-                sender.respond(&message, &P::TopologyOk {})?
+            P::PropagationStats {} => {
+                let stats = channel.propagation_stats();
+                respond(link.sender, P::PropagationStatsOk { stats })?
+            }
+            P::PropagationStatsOk { .. } => {}
+            P::Topology { topology } => {
+                respond(link.sender, P::TopologyOk {})?;
+                ctx.topology.provided(topology);
+                let routes = ctx.routes();
+                ctx.set_neighbors(routes)?;
             }
             P::TopologyOk {} => {}
         }
@@ -0,0 +1,50 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::values;
+
+/// Anything that can be broadcast
+pub trait Payload: Serialize + DeserializeOwned + Clone + Send + 'static {
+    /// Identifies a payload for deduplication. Two payloads with the same key are the same
+    /// broadcast.
+    fn key(&self) -> u64;
+    /// Encode a batch of payloads for gossip. By default this is just the list.
+    fn pack(values: &[Self]) -> serde_json::Result<Value> {
+        serde_json::to_value(values)
+    }
+    fn unpack(packed: Value) -> serde_json::Result<Vec<Self>> {
+        serde_json::from_value(packed)
+    }
+}
+
+/// The integers Maelstrom broadcasts are their own keys, and pack into runs
+impl Payload for u64 {
+    fn key(&self) -> u64 {
+        *self
+    }
+    fn pack(values: &[u64]) -> serde_json::Result<Value> {
+        serde_json::to_value(values::to_runs(values))
+    }
+    fn unpack(packed: Value) -> serde_json::Result<Vec<u64>> {
+        let runs: Vec<(u64, u64)> = serde_json::from_value(packed)?;
+        values::from_runs(&runs).ok_or_else(|| {
+            serde::de::Error::custom(format!(
+                "runs must not end before they start, or add up to more than {} values",
+                values::MAX_EXPANDED
+            ))
+        })
+    }
+}
+
+/// Arbitrary JSON is keyed by a hash of its serialization. Object keys are always serialized in
+/// order, so equal values hash the same.
+impl Payload for Value {
+    fn key(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.to_string().hash(&mut hasher);
+        hasher.finish()
+    }
+}
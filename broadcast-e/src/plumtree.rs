@@ -0,0 +1,181 @@
+//! Plumtree epidemic broadcast trees (Leitão, Pereira and Rodrigues, 2007).
+//!
+//! Values are eagerly pushed along a spanning tree made of the "eager" links, and only their ids
+//! are lazily announced over the remaining links. A node that hears about an id it has not
+//! received within the graft timeout grafts the announcing link into the tree, and a node that
+//! receives nothing but duplicates over a link prunes it out of the tree. This module only keeps
+//! track of the links; sending is left to the caller.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+pub struct Plumtree {
+    eager: Vec<String>,
+    lazy: Vec<String>,
+    /// Announced ids we have not received, when we first heard of them, and who announced them
+    missing: HashMap<u64, (Instant, Vec<String>)>,
+    /// Ids waiting to be announced to each lazy peer
+    announcements: HashMap<String, Vec<u64>>,
+    graft_timeout: Duration,
+}
+
+impl Plumtree {
+    /// Every neighbor starts out eager, and the tree is pruned down from there
+    pub fn new(neighbors: Vec<String>, graft_timeout: Duration) -> Plumtree {
+        Plumtree {
+            eager: neighbors,
+            lazy: vec![],
+            missing: HashMap::new(),
+            announcements: HashMap::new(),
+            graft_timeout,
+        }
+    }
+    /// Follow a topology change. New neighbors start out eager.
+    pub fn set_neighbors(&mut self, neighbors: &[String]) {
+        self.eager.retain(|n| neighbors.contains(n));
+        self.lazy.retain(|n| neighbors.contains(n));
+        self.announcements.retain(|n, _| neighbors.contains(n));
+        for neighbor in neighbors {
+            if !self.eager.contains(neighbor) && !self.lazy.contains(neighbor) {
+                self.eager.push(neighbor.clone());
+            }
+        }
+    }
+    /// A value arrived by any route, including anti-entropy, so there is no need to graft for it
+    pub fn received(&mut self, id: u64) {
+        self.missing.remove(&id);
+    }
+    /// A value was received for the first time from `from`, which is None for clients.
+    /// Returns the peers it should be eagerly pushed to.
+    pub fn delivered(&mut self, id: u64, from: Option<&str>) -> Vec<String> {
+        self.received(id);
+        for peer in self.lazy.iter().filter(|p| Some(p.as_str()) != from) {
+            self.announcements.entry(peer.clone()).or_default().push(id);
+        }
+        if let Some(from) = from {
+            self.make_eager(from);
+        }
+        self.eager
+            .iter()
+            .filter(|p| Some(p.as_str()) != from)
+            .cloned()
+            .collect()
+    }
+    /// Everything a peer eagerly pushed to us was a duplicate, so the link is redundant.
+    /// Returns whether the peer should be sent a prune.
+    pub fn duplicates_from(&mut self, from: &str) -> bool {
+        let was_eager = self.eager.iter().any(|p| p == from);
+        self.make_lazy(from);
+        was_eager
+    }
+    pub fn pruned(&mut self, from: &str) {
+        self.make_lazy(from);
+    }
+    /// A peer announced ids. Remember the ones we do not have, in case the tree never brings them.
+    pub fn announced(&mut self, ids: impl IntoIterator<Item = u64>, from: &str) {
+        for id in ids {
+            let (_, announcers) = self
+                .missing
+                .entry(id)
+                .or_insert_with(|| (Instant::now(), vec![]));
+            if !announcers.iter().any(|a| a == from) {
+                announcers.push(from.to_string());
+            }
+        }
+    }
+    pub fn grafted(&mut self, from: &str) {
+        self.make_eager(from);
+    }
+    /// Take the pending announcements for each lazy peer
+    pub fn take_announcements(&mut self) -> HashMap<String, Vec<u64>> {
+        std::mem::take(&mut self.announcements)
+    }
+    /// Find ids that the tree failed to deliver in time, and graft the first peer that announced
+    /// each of them into the tree. Returns the ids to request from each grafted peer.
+    pub fn expired(&mut self) -> HashMap<String, Vec<u64>> {
+        let mut grafts: HashMap<String, Vec<u64>> = HashMap::new();
+        for (id, (first_heard, announcers)) in self.missing.iter_mut() {
+            if first_heard.elapsed() < self.graft_timeout || announcers.is_empty() {
+                continue;
+            }
+            // Give the next announcer another full timeout before trying them
+            *first_heard = Instant::now();
+            let peer = announcers.remove(0);
+            grafts.entry(peer).or_default().push(*id);
+        }
+        self.missing
+            .retain(|_, (_, announcers)| !announcers.is_empty());
+        for peer in grafts.keys().cloned().collect::<Vec<_>>() {
+            self.make_eager(&peer);
+        }
+        grafts
+    }
+    fn make_eager(&mut self, peer: &str) {
+        self.lazy.retain(|p| p != peer);
+        if !self.eager.iter().any(|p| p == peer) {
+            self.eager.push(peer.to_string());
+        }
+    }
+    fn make_lazy(&mut self, peer: &str) {
+        self.eager.retain(|p| p != peer);
+        if !self.lazy.iter().any(|p| p == peer) {
+            self.lazy.push(peer.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree() -> Plumtree {
+        let neighbors = vec!["n1".to_string(), "n2".to_string()];
+        Plumtree::new(neighbors, Duration::ZERO)
+    }
+
+    #[test]
+    fn duplicates_prune_an_eager_link_once() {
+        let mut plumtree = tree();
+        assert!(plumtree.duplicates_from("n1"));
+        assert!(!plumtree.duplicates_from("n1"));
+        assert_eq!(plumtree.delivered(1, None), vec!["n2"]);
+        // The pruned peer is only told about the value
+        assert_eq!(plumtree.take_announcements()["n1"], vec![1]);
+    }
+
+    #[test]
+    fn delivery_from_a_lazy_peer_makes_it_eager() {
+        let mut plumtree = tree();
+        plumtree.pruned("n1");
+        assert_eq!(plumtree.delivered(1, Some("n1")), vec!["n2"]);
+        assert_eq!(plumtree.delivered(2, None), vec!["n2", "n1"]);
+    }
+
+    #[test]
+    fn missing_ids_graft_their_announcer() {
+        let mut plumtree = tree();
+        plumtree.pruned("n1");
+        plumtree.announced([7], "n1");
+        let grafts = plumtree.expired();
+        assert_eq!(grafts["n1"], vec![7]);
+        assert_eq!(plumtree.delivered(8, None), vec!["n2", "n1"]);
+        // Each announcer is only tried once
+        assert!(plumtree.expired().is_empty());
+    }
+
+    #[test]
+    fn values_received_any_way_are_not_grafted() {
+        let mut plumtree = tree();
+        plumtree.pruned("n1");
+        plumtree.announced([7], "n1");
+        plumtree.received(7);
+        assert!(plumtree.expired().is_empty());
+    }
+
+    #[test]
+    fn announcements_wait_for_the_graft_timeout() {
+        let mut plumtree = Plumtree::new(vec!["n1".to_string()], Duration::from_secs(60));
+        plumtree.pruned("n1");
+        plumtree.announced([7], "n1");
+        assert!(plumtree.expired().is_empty());
+    }
+}
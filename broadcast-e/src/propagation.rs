@@ -0,0 +1,201 @@
+//! How long values take to reach this node, and over how many hops.
+//!
+//! Values are stamped with the wall clock time at which a client first broadcast them, and every
+//! node records when each value first reached it. Maelstrom runs every node on the same machine,
+//! so the clocks agree and the difference is the propagation delay.
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Where a value came from, as sent along with it between peers
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Stamp {
+    /// Milliseconds since the epoch when a client broadcast the value
+    pub origin_ms: u64,
+    /// How many peer to peer messages the value has gone through
+    pub hops: u32,
+}
+
+impl Stamp {
+    /// A stamp for a value a client just broadcast
+    pub fn origin() -> Stamp {
+        Stamp {
+            origin_ms: now_ms(),
+            hops: 0,
+        }
+    }
+    pub fn next_hop(self) -> Stamp {
+        Stamp {
+            hops: self.hops + 1,
+            ..self
+        }
+    }
+}
+
+/// Percentiles of a set of samples
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Percentiles {
+    fn of(mut samples: Vec<f64>) -> Percentiles {
+        if samples.is_empty() {
+            return Percentiles::default();
+        }
+        samples.sort_by(f64::total_cmp);
+        let at = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+        Percentiles {
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            max: at(1.0),
+        }
+    }
+}
+
+/// A serializable summary of how values reached this node
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PropagationSummary {
+    /// Values that were broadcast through this node
+    pub originated: usize,
+    /// Values forwarded to this node by a peer, which the percentiles cover
+    pub forwarded: usize,
+    /// Values that only arrived through anti-entropy, which carries no stamps
+    pub unstamped: usize,
+    pub delay_ms: Percentiles,
+    pub hops: Percentiles,
+}
+
+/// A value's stamp as it reached this node, and when that was
+struct Seen {
+    stamp: Stamp,
+    at_ms: u64,
+}
+
+/// First sightings of every value, by key
+#[derive(Default)]
+pub struct Propagation {
+    seen: HashMap<u64, Seen>,
+    unstamped: usize,
+}
+
+impl Propagation {
+    /// A value reached this node for the first time, with the stamp it arrived with if any. Only
+    /// the first sighting of a key counts, so that a later copy cannot shorten its delay.
+    pub fn first_seen(&mut self, key: u64, stamp: Option<Stamp>) {
+        if self.seen.contains_key(&key) {
+            return;
+        }
+        match stamp {
+            Some(stamp) => {
+                let at_ms = now_ms();
+                self.seen.insert(key, Seen { stamp, at_ms });
+            }
+            None => self.unstamped += 1,
+        }
+    }
+    /// The stamp to send along with a value
+    pub fn stamp(&self, key: u64) -> Option<Stamp> {
+        self.seen.get(&key).map(|seen| seen.stamp)
+    }
+    pub fn summary(&self) -> PropagationSummary {
+        let forwarded: Vec<&Seen> = self
+            .seen
+            .values()
+            .filter(|seen| seen.stamp.hops > 0)
+            .collect();
+        PropagationSummary {
+            originated: self.seen.len() - forwarded.len(),
+            forwarded: forwarded.len(),
+            unstamped: self.unstamped,
+            delay_ms: Percentiles::of(
+                forwarded
+                    .iter()
+                    .map(|seen| seen.at_ms.saturating_sub(seen.stamp.origin_ms) as f64)
+                    .collect(),
+            ),
+            hops: Percentiles::of(
+                forwarded
+                    .iter()
+                    .map(|seen| seen.stamp.hops as f64)
+                    .collect(),
+            ),
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Clock is before the epoch")
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamp(hops: u32) -> Stamp {
+        Stamp {
+            origin_ms: now_ms(),
+            hops,
+        }
+    }
+
+    #[test]
+    fn next_hop_keeps_the_origin() {
+        let origin = Stamp::origin();
+        let next = origin.next_hop().next_hop();
+        assert_eq!(next.origin_ms, origin.origin_ms);
+        assert_eq!(next.hops, 2);
+    }
+
+    #[test]
+    fn percentiles_of_nothing_are_zero() {
+        let percentiles = Percentiles::of(vec![]);
+        assert_eq!(percentiles.p50, 0.0);
+        assert_eq!(percentiles.max, 0.0);
+    }
+
+    #[test]
+    fn percentiles_round_to_the_nearest_sample() {
+        let percentiles = Percentiles::of((1..=100).rev().map(f64::from).collect());
+        assert_eq!(percentiles.p50, 51.0);
+        assert_eq!(percentiles.p90, 90.0);
+        assert_eq!(percentiles.p99, 99.0);
+        assert_eq!(percentiles.max, 100.0);
+        let single = Percentiles::of(vec![7.0]);
+        assert_eq!((single.p50, single.p99, single.max), (7.0, 7.0, 7.0));
+    }
+
+    #[test]
+    fn only_the_first_sighting_counts() {
+        let mut propagation = Propagation::default();
+        propagation.first_seen(1, Some(stamp(3)));
+        propagation.first_seen(1, Some(stamp(1)));
+        propagation.first_seen(1, None);
+        assert_eq!(propagation.stamp(1).map(|stamp| stamp.hops), Some(3));
+        assert_eq!(propagation.summary().unstamped, 0);
+    }
+
+    #[test]
+    fn summary_splits_values_by_how_they_arrived() {
+        let mut propagation = Propagation::default();
+        propagation.first_seen(1, Some(stamp(0)));
+        propagation.first_seen(2, Some(stamp(1)));
+        propagation.first_seen(3, Some(stamp(3)));
+        propagation.first_seen(4, None);
+        let summary = propagation.summary();
+        assert_eq!(summary.originated, 1);
+        assert_eq!(summary.forwarded, 2);
+        assert_eq!(summary.unstamped, 1);
+        assert_eq!(summary.hops.p50, 3.0);
+        assert_eq!(summary.hops.max, 3.0);
+        assert!(summary.delay_ms.max < 1000.0);
+    }
+}
@@ -0,0 +1,80 @@
+//! Reads that wait for a stronger guarantee than "whatever this node has right now".
+//!
+//! A read-your-writes read waits until the node holds every value the client lists as written
+//! through other nodes. Values written through this node are already here by the time the write
+//! is acknowledged, so they never need waiting for. A bounded staleness read waits until every
+//! value that arrived more than `max_staleness_ms` before the read has been confirmed by a
+//! majority of neighbors. Either way the read is answered after `timeout_ms` with
+//! whatever the node has, so a partition slows reads down but never blocks them.
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::payload::Payload;
+
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadMode {
+    /// Answer immediately from local state
+    #[default]
+    Local,
+    ReadYourWrites,
+    BoundedStaleness,
+}
+
+/// What a pending read is waiting for
+pub enum Wait {
+    /// Every one of these keys to arrive
+    Keys(Vec<u64>),
+    /// Every value that arrived before this instant to be confirmed by a quorum
+    ConfirmedBefore(Instant),
+}
+
+impl Wait {
+    /// What a read-your-writes read waits for, given the values its client wrote elsewhere
+    pub fn written<T: Payload>(values: &[T]) -> Wait {
+        Wait::Keys(values.iter().map(Payload::key).collect())
+    }
+}
+
+/// A read that could not be answered straight away
+pub struct PendingRead {
+    pub client: String,
+    pub msg_id: Option<u64>,
+    pub wait: Wait,
+    pub deadline: Instant,
+}
+
+/// P::ReadOk, but borrowing the values so that reads do not have to clone them
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+pub struct ReadOkRef<'a, T> {
+    pub messages: &'a [T],
+}
+
+/// How many neighbors must have a value before it counts as confirmed. A node without neighbors
+/// has nobody to confirm with, so everything counts.
+pub fn quorum(neighbors: usize) -> usize {
+    (neighbors / 2 + 1).min(neighbors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quorum_is_a_majority_of_neighbors() {
+        let quorums: Vec<usize> = (0..6).map(quorum).collect();
+        assert_eq!(quorums, vec![0, 1, 2, 2, 3, 3]);
+    }
+
+    #[test]
+    fn read_your_writes_waits_for_the_listed_keys() {
+        let Wait::Keys(keys) = Wait::written(&[3_u64, 1]) else {
+            panic!("Expected to wait for keys");
+        };
+        assert_eq!(keys, vec![3, 1]);
+    }
+}
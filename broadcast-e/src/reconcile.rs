@@ -0,0 +1,249 @@
+//! Set reconciliation over u64 values by comparing digests of ranges of hash space.
+//!
+//! Each side hashes its values into the full u64 range and summarizes any range by how many
+//! values fall in it and the xor of their hashes. Ranges whose digests match are assumed equal.
+//! Ranges that differ are split into FANOUT subranges and compared again, until one side has at
+//! most LEAF_SIZE values in a range and just ships them. Traffic is proportional to the number of
+//! differences rather than the size of the sets, at the cost of a round trip per level.
+use serde::{Deserialize, Serialize};
+
+const FANOUT: u64 = 16;
+const LEAF_SIZE: u64 = 16;
+
+/// A summary of the values whose hashes fall in start..=end
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangeDigest {
+    pub start: u64,
+    pub end: u64,
+    pub count: u64,
+    pub hash: u64,
+}
+
+/// What to do about a range after comparing digests with a peer
+pub enum Step {
+    /// Both sides have the same values
+    Match,
+    /// We have few enough values to send them all, which are included
+    Ship(Vec<u64>),
+    /// The peer has fewer values, so send it our digest and let it ship
+    Ask(RangeDigest),
+    /// Both sides have lots of values, so compare these smaller ranges next
+    Split(Vec<RangeDigest>),
+}
+
+/// Values sorted by hash, with running xors of the hashes so that any range is digested with two
+/// binary searches instead of a scan
+#[derive(Default)]
+pub struct Index {
+    /// (hash, value) pairs, sorted by hash
+    sorted: Vec<(u64, u64)>,
+    /// prefix[i] is the xor of the first i hashes in sorted
+    prefix: Vec<u64>,
+    /// Values inserted since the last digest, merged in before the next one
+    fresh: Vec<(u64, u64)>,
+}
+
+impl Index {
+    pub fn insert(&mut self, value: u64) {
+        self.fresh.push((hash(value), value));
+    }
+    /// Merge in fresh values, so that inserting stays cheap between digests
+    fn settle(&mut self) {
+        if self.fresh.is_empty() && !self.prefix.is_empty() {
+            return;
+        }
+        self.sorted.append(&mut self.fresh);
+        self.sorted.sort_unstable();
+        self.sorted.dedup();
+        self.prefix = std::iter::once(0)
+            .chain(self.sorted.iter().scan(0, |xor, (h, _)| {
+                *xor ^= h;
+                Some(*xor)
+            }))
+            .collect();
+    }
+    /// The positions in sorted of the values whose hashes fall in start..=end
+    fn bounds(&mut self, start: u64, end: u64) -> (usize, usize) {
+        self.settle();
+        let from = self.sorted.partition_point(|(h, _)| *h < start);
+        let to = self.sorted.partition_point(|(h, _)| *h <= end);
+        (from, to.max(from))
+    }
+    /// The digest of every value
+    pub fn root(&mut self) -> RangeDigest {
+        self.digest(0, u64::MAX)
+    }
+    pub fn digest(&mut self, start: u64, end: u64) -> RangeDigest {
+        let (from, to) = self.bounds(start, end);
+        RangeDigest {
+            start,
+            end,
+            count: (to - from) as u64,
+            hash: self.prefix[to] ^ self.prefix[from],
+        }
+    }
+    pub fn values_in(&mut self, start: u64, end: u64) -> Vec<u64> {
+        let (from, to) = self.bounds(start, end);
+        self.sorted[from..to]
+            .iter()
+            .map(|(_, value)| *value)
+            .collect()
+    }
+    /// Compare a peer's digest against our own for the same range
+    pub fn compare(&mut self, theirs: &RangeDigest) -> Step {
+        let ours = self.digest(theirs.start, theirs.end);
+        if ours == *theirs {
+            Step::Match
+        } else if ours.count <= LEAF_SIZE && ours.count <= theirs.count {
+            Step::Ship(self.values_in(ours.start, ours.end))
+        } else if theirs.count <= LEAF_SIZE {
+            Step::Ask(ours)
+        } else {
+            Step::Split(
+                split(ours.start, ours.end)
+                    .into_iter()
+                    .map(|(start, end)| self.digest(start, end))
+                    .collect(),
+            )
+        }
+    }
+}
+
+/// splitmix64, which is a bijection, so distinct values never share a hash
+fn hash(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Cut start..=end into FANOUT roughly equal pieces
+fn split(start: u64, end: u64) -> Vec<(u64, u64)> {
+    let width = (end - start) as u128 + 1;
+    let step = width.div_ceil(FANOUT as u128);
+    let mut pieces = vec![];
+    let mut piece_start = start as u128;
+    while piece_start <= end as u128 {
+        let piece_end = (piece_start + step - 1).min(end as u128);
+        pieces.push((piece_start as u64, piece_end as u64));
+        piece_start = piece_end + 1;
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(values: impl IntoIterator<Item = u64>) -> Index {
+        let mut index = Index::default();
+        for value in values {
+            index.insert(value);
+        }
+        index
+    }
+
+    /// Play out the exchange Channel::sync and Channel::sync_values have, starting with `a`
+    /// sending its root digest, until neither side has anything left to send
+    fn reconcile(a: &mut Index, b: &mut Index) -> usize {
+        let mut digests = vec![a.root()];
+        let (mut from, mut to) = (a, b);
+        let mut rounds = 0;
+        while !digests.is_empty() {
+            rounds += 1;
+            assert!(rounds < 100, "reconciliation did not finish");
+            let mut next = vec![];
+            for digest in &digests {
+                match to.compare(digest) {
+                    Step::Match => {}
+                    Step::Ship(values) => {
+                        // The other side takes the values and sends back whatever we lacked
+                        let missing: Vec<u64> = from
+                            .values_in(digest.start, digest.end)
+                            .into_iter()
+                            .filter(|value| !values.contains(value))
+                            .collect();
+                        for value in values {
+                            if !from.values_in(digest.start, digest.end).contains(&value) {
+                                from.insert(value);
+                            }
+                        }
+                        for value in missing {
+                            to.insert(value);
+                        }
+                    }
+                    Step::Ask(ours) => next.push(ours),
+                    Step::Split(subranges) => next.extend(subranges),
+                }
+            }
+            digests = next;
+            std::mem::swap(&mut from, &mut to);
+        }
+        rounds
+    }
+
+    fn everything(index: &mut Index) -> Vec<u64> {
+        let mut values = index.values_in(0, u64::MAX);
+        values.sort_unstable();
+        values
+    }
+
+    #[test]
+    fn diverged_sets_converge() {
+        let mut a = index((0..5000).chain(10_000..10_040));
+        let mut b = index((0..4990).chain(20_000..20_030));
+        assert!(
+            reconcile(&mut a, &mut b) > 2,
+            "large differences should split"
+        );
+        let expected: Vec<u64> = (0..5000)
+            .chain(10_000..10_040)
+            .chain(20_000..20_030)
+            .collect();
+        assert_eq!(everything(&mut a), expected);
+        assert_eq!(everything(&mut b), expected);
+    }
+
+    #[test]
+    fn equal_sets_match_at_the_root() {
+        let mut a = index(0..1000);
+        let mut b = index((0..1000).rev());
+        assert_eq!(reconcile(&mut a, &mut b), 1);
+        assert_eq!(a.root(), b.root());
+    }
+
+    #[test]
+    fn empty_sets() {
+        let (mut a, mut b) = (index([]), index([]));
+        assert_eq!(a.root().count, 0);
+        assert_eq!(reconcile(&mut a, &mut b), 1);
+        let mut b = index(0..100);
+        reconcile(&mut a, &mut b);
+        assert_eq!(everything(&mut a), (0..100).collect::<Vec<_>>());
+        let mut a = index([]);
+        reconcile(&mut b, &mut a);
+        assert_eq!(everything(&mut a), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn one_sided_ranges() {
+        // Every value on one side, which the other side has none of in most ranges
+        let mut a = index(0..3);
+        let mut b = index(0..3000);
+        reconcile(&mut a, &mut b);
+        assert_eq!(everything(&mut a), everything(&mut b));
+        assert_eq!(a.root(), b.root());
+    }
+
+    #[test]
+    fn digests_only_cover_their_range() {
+        let mut a = index(0..1000);
+        let pieces = split(0, u64::MAX);
+        assert_eq!(pieces.len() as u64, FANOUT);
+        assert_eq!(pieces.last().unwrap().1, u64::MAX);
+        let digests: Vec<RangeDigest> = pieces.iter().map(|&(s, e)| a.digest(s, e)).collect();
+        assert_eq!(digests.iter().map(|d| d.count).sum::<u64>(), 1000);
+        let xor = digests.iter().fold(0, |xor, d| xor ^ d.hash);
+        assert_eq!(xor, a.root().hash);
+    }
+}
@@ -1,9 +1,20 @@
-use std::{collections::hash_map::DefaultHasher, io::Write};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    time::{Duration, Instant},
+};
 
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use serde_json::Result;
+use serde_json::{Result, Value};
 use std::hash::{Hash, Hasher};
 
+use crate::detector::{Change, FailureDetector};
+use crate::latency::{Latency, LatencySummary};
+
+/// How often neighbors are pinged by default, which doubles as the failure detector's heartbeat
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// Pings that have gone unanswered for this long are forgotten
+const PING_EXPIRY: Duration = Duration::from_secs(30);
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message<T> {
     pub src: String,
@@ -19,6 +30,36 @@ pub struct Body<T> {
     pub fields: T,
 }
 
+impl<T> Message<T> {
+    /// Swap out the body fields, keeping the routing and ids
+    fn with_fields<U>(self, fields: U) -> Message<U> {
+        Message {
+            src: self.src,
+            dest: self.dest,
+            body: Body {
+                msg_id: self.body.msg_id,
+                in_reply_to: self.body.in_reply_to,
+                fields,
+            },
+        }
+    }
+    /// Separate the fields from the rest of the message, so that they can be consumed while
+    /// still being able to respond
+    pub fn split(self) -> (Message<()>, T) {
+        let Message { src, dest, body } = self;
+        let header = Message {
+            src,
+            dest,
+            body: Body {
+                msg_id: body.msg_id,
+                in_reply_to: body.in_reply_to,
+                fields: (),
+            },
+        };
+        (header, body.fields)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
@@ -30,6 +71,39 @@ enum InitPayload {
     InitOk {},
 }
 
+/// Messages that every node answers, regardless of workload
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimePayload {
+    Ping {},
+    Pong {},
+    Latency {},
+    LatencyOk {
+        peers: HashMap<String, LatencySummary>,
+    },
+    Membership {},
+    MembershipOk {
+        suspected: Vec<String>,
+        phi: HashMap<String, f64>,
+    },
+}
+
+impl RuntimePayload {
+    fn handles(r#type: &str) -> bool {
+        matches!(
+            r#type,
+            "ping" | "pong" | "latency" | "latency_ok" | "membership" | "membership_ok"
+        )
+    }
+}
+
+/// A message destined either for the runtime or for the workload
+pub enum Incoming<T> {
+    Runtime(Message<RuntimePayload>),
+    Workload(Message<T>),
+}
+
 pub struct Server {}
 
 impl Server {
@@ -44,12 +118,38 @@ impl Server {
         let mut deserializer = serde_json::Deserializer::from_reader(stdin);
         Message::deserialize(&mut deserializer)
     }
+    /// Read a message, separating out the ones that should go to Sender::handle_runtime
+    pub fn read_incoming<T: DeserializeOwned>(&self) -> Result<Incoming<T>> {
+        let mut message: Message<Value> = self.read_message()?;
+        let is_runtime = message
+            .body
+            .fields
+            .get("type")
+            .and_then(Value::as_str)
+            .is_some_and(RuntimePayload::handles);
+        let fields = message.body.fields.take();
+        if is_runtime {
+            Ok(Incoming::Runtime(
+                message.with_fields(serde_json::from_value(fields)?),
+            ))
+        } else {
+            Ok(Incoming::Workload(
+                message.with_fields(serde_json::from_value(fields)?),
+            ))
+        }
+    }
 }
 
 pub struct Sender {
     pub node_id: String,
     pub node_ids: Vec<String>,
+    pub latency: Latency,
+    pub detector: FailureDetector,
     counter: u64,
+    pings: HashMap<u64, (String, Instant)>,
+    /// Messages that would have been written to stdout, so that tests can inspect them
+    #[cfg(test)]
+    pub sent: Vec<Value>,
 }
 
 impl Sender {
@@ -63,7 +163,12 @@ impl Sender {
                 Sender {
                     node_id: node_id.clone(),
                     node_ids: node_ids.clone(),
+                    latency: Latency::default(),
+                    detector: FailureDetector::new(PROBE_INTERVAL),
                     counter,
+                    pings: HashMap::new(),
+                    #[cfg(test)]
+                    sent: vec![],
                 }
             }
             _ => panic!("Invalid init message"),
@@ -73,8 +178,34 @@ impl Sender {
         sender.respond(init_message, init_ok)?;
         Ok(sender)
     }
+    /// A sender for `node_id` that records what it sends instead of writing it out
+    #[cfg(test)]
+    pub fn for_test(node_id: &str, node_ids: &[&str]) -> Sender {
+        Sender {
+            node_id: node_id.to_string(),
+            node_ids: node_ids.iter().map(|n| n.to_string()).collect(),
+            latency: Latency::default(),
+            detector: FailureDetector::new(PROBE_INTERVAL),
+            counter: 0,
+            pings: HashMap::new(),
+            sent: vec![],
+        }
+    }
+    /// Take every message sent since the last call
+    #[cfg(test)]
+    pub fn take_sent(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.sent)
+    }
+    /// Record a message instead of writing it to stdout
+    #[cfg(test)]
+    pub fn send_message<T: Serialize>(&mut self, message: &Message<T>) -> Result<()> {
+        self.sent.push(serde_json::to_value(message)?);
+        Ok(())
+    }
     /// Write a message directly to stdout
-    pub fn send_message<T: Serialize>(&self, message: &Message<T>) -> Result<()> {
+    #[cfg(not(test))]
+    pub fn send_message<T: Serialize>(&mut self, message: &Message<T>) -> Result<()> {
+        use std::io::Write;
         let stdout = std::io::stdout().lock();
         let mut serializer = serde_json::Serializer::new(stdout);
         message.serialize(&mut serializer)?;
@@ -123,6 +254,51 @@ impl Sender {
         let message = self.response(to, fields)?;
         self.send_message(&message)
     }
+    /// Ping `peers` to keep their round trip times fresh, and report any of them whose
+    /// suspected or alive state changed since the last probe. Only the peers pinged are watched
+    /// by the failure detector, since nobody else is sending it heartbeats.
+    pub fn probe(&mut self, peers: &[String]) -> Result<Vec<Change>> {
+        self.pings
+            .retain(|_, (_, sent_at)| sent_at.elapsed() < PING_EXPIRY);
+        for peer in peers {
+            let message = self.message(peer, RuntimePayload::Ping {})?;
+            self.pings.insert(
+                message.body.msg_id.expect("No msg_id???"),
+                (peer.clone(), Instant::now()),
+            );
+            self.send_message(&message)?;
+        }
+        self.detector.watch(peers);
+        Ok(self.detector.check())
+    }
+    /// Answer pings and latency queries, and record the round trip of pongs
+    pub fn handle_runtime(&mut self, message: &Message<RuntimePayload>) -> Result<()> {
+        match message.body.fields {
+            RuntimePayload::Ping {} => self.respond(message, RuntimePayload::Pong {}),
+            RuntimePayload::Pong {} => {
+                if let Some((peer, sent_at)) = message
+                    .body
+                    .in_reply_to
+                    .and_then(|msg_id| self.pings.remove(&msg_id))
+                {
+                    self.latency.record(&peer, sent_at.elapsed());
+                    self.detector.heartbeat(&peer);
+                }
+                Ok(())
+            }
+            RuntimePayload::Latency {} => {
+                let peers = self.latency.summary();
+                self.respond(message, RuntimePayload::LatencyOk { peers })
+            }
+            RuntimePayload::LatencyOk { .. } => Ok(()),
+            RuntimePayload::Membership {} => {
+                let suspected = self.detector.suspected();
+                let phi = self.detector.phi();
+                self.respond(message, RuntimePayload::MembershipOk { suspected, phi })
+            }
+            RuntimePayload::MembershipOk { .. } => Ok(()),
+        }
+    }
 }
 
 pub fn init() -> Result<(Server, Sender)> {
@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+
+/// A strategy for choosing which nodes a node gossips with
+pub trait Topology: Send {
+    /// Pick the neighbors of `node`, given every node in the cluster
+    fn neighbors(&self, node: &str, node_ids: &[String]) -> Vec<String>;
+    /// Called with the topology Maelstrom sends at the start of a run. Most strategies ignore it.
+    fn provided(&mut self, _topology: HashMap<String, Vec<String>>) {}
+}
+
+/// Pick a topology from a config string like "hypercube" or "tree:4". Prefixing a strategy with
+/// "maelstrom+" adds its links on top of Maelstrom's topology. An empty or unrecognized config
+/// uses Maelstrom's topology as it is, so every other strategy has to be opted into.
+pub fn from_config(config: &str) -> Box<dyn Topology> {
+    if let Some(extra) = config.strip_prefix("maelstrom+") {
+        return Box::new(Augmented {
+            provided: Provided::default(),
+            extra: from_config(extra),
+        });
+    }
+    let (name, arg) = match config.split_once(':') {
+        Some((name, arg)) => (name, arg.parse().ok()),
+        None => (config, None),
+    };
+    match name {
+        "sane" => Box::new(Sane {}),
+        "ring" => Box::new(Ring {}),
+        "hypercube" => Box::new(Hypercube {}),
+        "tree" => Box::new(Tree {
+            arity: arg.unwrap_or(4),
+        }),
+        "star" => Box::new(Star {
+            hubs: arg.unwrap_or(3),
+        }),
+        "random" => Box::new(Random {
+            degree: arg.unwrap_or(4),
+        }),
+        _ => Box::<Provided>::default(),
+    }
+}
+
+fn index_of(node: &str, node_ids: &[String]) -> usize {
+    node_ids
+        .iter()
+        .position(|n| n == node)
+        .expect("node_id was not in the node_ids list")
+}
+
+/// Turn a list of indexes into a list of distinct node ids, never including `me`
+fn nodes(me: usize, indexes: impl IntoIterator<Item = usize>, node_ids: &[String]) -> Vec<String> {
+    let mut indexes: Vec<usize> = indexes.into_iter().filter(|&i| i != me).collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+    indexes.into_iter().map(|i| node_ids[i].clone()).collect()
+}
+
+/// The next node, plus the node four ahead for every even node, so that any node can be reached
+/// from any other in a handful of hops
+pub struct Sane {}
+
+impl Topology for Sane {
+    fn neighbors(&self, node: &str, node_ids: &[String]) -> Vec<String> {
+        let n = node_ids.len();
+        let me = index_of(node, node_ids);
+        let mut indexes = vec![(me + 1) % n];
+        if me.is_multiple_of(2) {
+            indexes.push((me + 4) % n);
+        }
+        nodes(me, indexes, node_ids)
+    }
+}
+
+/// Whatever Maelstrom sent in the topology message
+#[derive(Default)]
+pub struct Provided {
+    topology: HashMap<String, Vec<String>>,
+}
+
+impl Topology for Provided {
+    fn neighbors(&self, node: &str, _node_ids: &[String]) -> Vec<String> {
+        self.topology.get(node).cloned().unwrap_or_default()
+    }
+    fn provided(&mut self, topology: HashMap<String, Vec<String>>) {
+        self.topology = topology;
+    }
+}
+
+/// Maelstrom's topology, plus the links from another strategy
+pub struct Augmented {
+    provided: Provided,
+    extra: Box<dyn Topology>,
+}
+
+impl Topology for Augmented {
+    fn neighbors(&self, node: &str, node_ids: &[String]) -> Vec<String> {
+        let mut neighbors = self.provided.neighbors(node, node_ids);
+        for neighbor in self.extra.neighbors(node, node_ids) {
+            if !neighbors.contains(&neighbor) {
+                neighbors.push(neighbor);
+            }
+        }
+        neighbors
+    }
+    fn provided(&mut self, topology: HashMap<String, Vec<String>>) {
+        self.provided.provided(topology);
+    }
+}
+
+/// The nodes on either side
+pub struct Ring {}
+
+impl Topology for Ring {
+    fn neighbors(&self, node: &str, node_ids: &[String]) -> Vec<String> {
+        let n = node_ids.len();
+        let me = index_of(node, node_ids);
+        nodes(me, [(me + 1) % n, (me + n - 1) % n], node_ids)
+    }
+}
+
+/// The nodes a power of two away in either direction
+pub struct Hypercube {}
+
+impl Topology for Hypercube {
+    fn neighbors(&self, node: &str, node_ids: &[String]) -> Vec<String> {
+        let n = node_ids.len();
+        let me = index_of(node, node_ids);
+        let mut indexes = vec![];
+        let mut pow_two = 1;
+        while pow_two < n {
+            indexes.push((me + pow_two) % n);
+            indexes.push((me + n - pow_two) % n);
+            pow_two *= 2;
+        }
+        nodes(me, indexes, node_ids)
+    }
+}
+
+/// A spanning tree rooted at the first node, where every node has `arity` children
+pub struct Tree {
+    pub arity: usize,
+}
+
+impl Topology for Tree {
+    fn neighbors(&self, node: &str, node_ids: &[String]) -> Vec<String> {
+        let n = node_ids.len();
+        let arity = self.arity.max(1);
+        let me = index_of(node, node_ids);
+        let parent = (me > 0).then(|| (me - 1) / arity);
+        let children = (me * arity + 1..=me * arity + arity).filter(|&i| i < n);
+        nodes(me, parent.into_iter().chain(children), node_ids)
+    }
+}
+
+/// The first `hubs` nodes are connected to everyone, and everyone else only to the hubs
+pub struct Star {
+    pub hubs: usize,
+}
+
+impl Topology for Star {
+    fn neighbors(&self, node: &str, node_ids: &[String]) -> Vec<String> {
+        let n = node_ids.len();
+        let hubs = self.hubs.clamp(1, n);
+        let me = index_of(node, node_ids);
+        if me < hubs {
+            nodes(me, 0..n, node_ids)
+        } else {
+            nodes(me, 0..hubs, node_ids)
+        }
+    }
+}
+
+/// A random graph where every node has `degree` neighbors, or at least two since the graph is
+/// built around a cycle. That is as far as possible: a node cannot have more neighbors than
+/// there are other nodes, and when the cluster size and the degree are both odd, one node has to
+/// go one short. Every node seeds the generator the same way,
+/// so they all agree on the graph.
+pub struct Random {
+    pub degree: usize,
+}
+
+impl Random {
+    /// Every node's neighbors, by index
+    fn graph(&self, n: usize) -> Vec<Vec<usize>> {
+        let degree = self.degree.clamp(2, n.saturating_sub(1).max(2));
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64 ^ n as u64;
+        let mut next = move || {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+        let mut graph: Vec<Vec<usize>> = vec![vec![]; n];
+        let link = |graph: &mut Vec<Vec<usize>>, a: usize, b: usize| {
+            graph[a].push(b);
+            graph[b].push(a);
+        };
+        // A random Hamiltonian cycle keeps the graph connected
+        let mut cycle: Vec<usize> = (0..n).collect();
+        for i in (1..n).rev() {
+            cycle.swap(i, next() % (i + 1));
+        }
+        for i in 0..n {
+            let (a, b) = (cycle[i], cycle[(i + 1) % n]);
+            if a != b && !graph[a].contains(&b) {
+                link(&mut graph, a, b);
+            }
+        }
+        // Then random edges between nodes that are still short, until none are or every pair of
+        // them is already linked
+        loop {
+            let short: Vec<usize> = (0..n).filter(|&i| graph[i].len() < degree).collect();
+            let open: Vec<(usize, usize)> = short
+                .iter()
+                .enumerate()
+                .flat_map(|(i, &a)| short[i + 1..].iter().map(move |&b| (a, b)))
+                .filter(|&(a, b)| !graph[a].contains(&b))
+                .collect();
+            if open.is_empty() {
+                break;
+            }
+            let (a, b) = open[next() % open.len()];
+            link(&mut graph, a, b);
+        }
+        // The short nodes left are all linked to each other already, but two of them (or one
+        // that is two short) can still split an edge between two other nodes, which keeps the
+        // degree of those the same
+        loop {
+            let short: Vec<usize> = (0..n).filter(|&i| graph[i].len() < degree).collect();
+            let (a, b) = match short[..] {
+                [a, b, ..] => (a, b),
+                [a] if graph[a].len() + 2 <= degree => (a, a),
+                _ => break,
+            };
+            let split = (0..n)
+                .flat_map(|c| graph[c].iter().map(move |&d| (c, d)))
+                .find(|&(c, d)| {
+                    ![a, b].contains(&c)
+                        && ![a, b].contains(&d)
+                        && !graph[a].contains(&c)
+                        && !graph[b].contains(&d)
+                });
+            let Some((c, d)) = split else {
+                break;
+            };
+            graph[c].retain(|&x| x != d);
+            graph[d].retain(|&x| x != c);
+            link(&mut graph, a, c);
+            link(&mut graph, b, d);
+        }
+        graph
+    }
+}
+
+impl Topology for Random {
+    fn neighbors(&self, node: &str, node_ids: &[String]) -> Vec<String> {
+        let me = index_of(node, node_ids);
+        let graph = self.graph(node_ids.len());
+        nodes(me, graph[me].iter().copied(), node_ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_ids(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("n{}", i)).collect()
+    }
+
+    /// Every node's neighbors, by index
+    fn graph(topology: &dyn Topology, node_ids: &[String]) -> Vec<Vec<usize>> {
+        node_ids
+            .iter()
+            .map(|node| {
+                topology
+                    .neighbors(node, node_ids)
+                    .iter()
+                    .map(|n| index_of(n, node_ids))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Whether a value forwarded along neighbor lists from any node reaches every other node
+    fn connected(graph: &[Vec<usize>]) -> bool {
+        (0..graph.len()).all(|origin| {
+            let mut reached = vec![false; graph.len()];
+            let mut queue = vec![origin];
+            reached[origin] = true;
+            while let Some(node) = queue.pop() {
+                for &neighbor in &graph[node] {
+                    if !reached[neighbor] {
+                        reached[neighbor] = true;
+                        queue.push(neighbor);
+                    }
+                }
+            }
+            reached.iter().all(|&r| r)
+        })
+    }
+
+    fn degrees(graph: &[Vec<usize>]) -> Vec<usize> {
+        graph.iter().map(Vec::len).collect()
+    }
+
+    fn check(config: &str, n: usize) -> Vec<Vec<usize>> {
+        let node_ids = node_ids(n);
+        let graph = graph(from_config(config).as_ref(), &node_ids);
+        for (i, neighbors) in graph.iter().enumerate() {
+            assert!(!neighbors.contains(&i), "{} links n{} to itself", config, i);
+            let mut distinct = neighbors.clone();
+            distinct.sort_unstable();
+            distinct.dedup();
+            assert_eq!(
+                distinct.len(),
+                neighbors.len(),
+                "{} repeats a neighbor",
+                config
+            );
+        }
+        assert!(
+            connected(&graph),
+            "{} is disconnected at {} nodes",
+            config,
+            n
+        );
+        graph
+    }
+
+    #[test]
+    fn every_strategy_connects_every_size() {
+        for config in [
+            "sane",
+            "ring",
+            "hypercube",
+            "tree:2",
+            "tree:4",
+            "star:1",
+            "star:3",
+        ] {
+            for n in [2, 3, 5, 8, 25] {
+                check(config, n);
+            }
+        }
+        for degree in 2..=7 {
+            for n in [2, 3, 5, 8, 25] {
+                check(&format!("random:{}", degree), n);
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_degrees() {
+        let n = 25;
+        assert!(degrees(&check("ring", n)).iter().all(|&d| d == 2));
+        // 1, 2, 4, 8 and 16 in either direction
+        assert!(degrees(&check("hypercube", n)).iter().all(|&d| d == 10));
+        let tree = degrees(&check("tree:4", n));
+        assert_eq!(tree[0], 4);
+        assert!(tree.iter().all(|&d| (1..=5).contains(&d)));
+        let star = degrees(&check("star:3", n));
+        assert_eq!(&star[..3], &[24, 24, 24]);
+        assert!(star[3..].iter().all(|&d| d == 3));
+    }
+
+    #[test]
+    fn random_is_regular() {
+        for n in [5, 8, 24, 25, 50] {
+            for degree in 2..=7 {
+                let graph = check(&format!("random:{}", degree), n);
+                let degree = degree.min(n - 1);
+                let mut degrees = degrees(&graph);
+                degrees.sort_unstable();
+                let short = (n * degree) % 2;
+                assert!(
+                    degrees[..short].iter().all(|&d| d == degree - 1),
+                    "{:?}",
+                    degrees
+                );
+                assert!(
+                    degrees[short..].iter().all(|&d| d == degree),
+                    "{:?}",
+                    degrees
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn random_links_are_mutual() {
+        let graph = check("random:4", 25);
+        for (a, neighbors) in graph.iter().enumerate() {
+            assert!(neighbors.iter().all(|&b| graph[b].contains(&a)));
+        }
+    }
+
+    #[test]
+    fn provided_follows_maelstrom() {
+        let node_ids = node_ids(3);
+        let mut topology = from_config("maelstrom+ring");
+        assert_eq!(
+            graph(topology.as_ref(), &node_ids),
+            vec![vec![1, 2], vec![0, 2], vec![0, 1]]
+        );
+        let provided = HashMap::from([("n0".to_string(), vec!["n2".to_string()])]);
+        topology.provided(provided.clone());
+        assert_eq!(topology.neighbors("n0", &node_ids), vec!["n2", "n1"]);
+        let mut topology = from_config("");
+        assert!(topology.neighbors("n0", &node_ids).is_empty());
+        topology.provided(provided);
+        assert_eq!(topology.neighbors("n0", &node_ids), vec!["n2"]);
+        assert!(topology.neighbors("n1", &node_ids).is_empty());
+    }
+}
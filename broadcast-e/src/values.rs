@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+/// Every broadcast value seen so far, with constant time lookups by key and reads in the order
+/// the values arrived
+pub struct ValueSet<T> {
+    seen: HashMap<u64, usize>,
+    ordered: Vec<T>,
+}
+
+impl<T> Default for ValueSet<T> {
+    fn default() -> Self {
+        ValueSet {
+            seen: HashMap::new(),
+            ordered: vec![],
+        }
+    }
+}
+
+impl<T> ValueSet<T> {
+    /// Add a value under its deduplication key, returning whether it was new
+    pub fn insert(&mut self, key: u64, value: T) -> bool {
+        if self.seen.contains_key(&key) {
+            return false;
+        }
+        self.seen.insert(key, self.ordered.len());
+        self.ordered.push(value);
+        true
+    }
+    pub fn contains(&self, key: &u64) -> bool {
+        self.seen.contains_key(key)
+    }
+    pub fn get(&self, key: &u64) -> Option<&T> {
+        self.seen.get(key).map(|&index| &self.ordered[index])
+    }
+    pub fn as_slice(&self) -> &[T] {
+        &self.ordered
+    }
+}
+
+/// Compress values into sorted, inclusive runs of consecutive integers, so that
+/// [5, 1, 2, 3, 7] becomes [(1, 3), (5, 5), (7, 7)]
+pub fn to_runs(values: &[u64]) -> Vec<(u64, u64)> {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+    let mut runs: Vec<(u64, u64)> = vec![];
+    for value in sorted {
+        match runs.last_mut() {
+            Some((_, end)) if end.checked_add(1) == Some(value) => *end = value,
+            _ => runs.push((value, value)),
+        }
+    }
+    runs
+}
+
+/// The most values runs may expand to. Runs come from peers, and without a cap a single
+/// malformed one like (0, u64::MAX) would keep a node busy forever.
+pub const MAX_EXPANDED: u64 = 1 << 24;
+
+/// Expand runs produced by to_runs back into the individual values. None if a run ends before
+/// it starts, or the runs hold more than MAX_EXPANDED values between them.
+pub fn from_runs(runs: &[(u64, u64)]) -> Option<Vec<u64>> {
+    let mut total: u64 = 0;
+    for &(start, end) in runs {
+        let length = end.checked_sub(start)?.checked_add(1)?;
+        total = total.checked_add(length).filter(|&t| t <= MAX_EXPANDED)?;
+    }
+    Some(runs.iter().flat_map(|&(start, end)| start..=end).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(values: &[u64]) -> Vec<u64> {
+        from_runs(&to_runs(values)).unwrap()
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(to_runs(&[]), vec![]);
+        assert_eq!(round_trip(&[]), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn single_value() {
+        assert_eq!(to_runs(&[7]), vec![(7, 7)]);
+        assert_eq!(round_trip(&[7]), vec![7]);
+    }
+
+    #[test]
+    fn adjacent_runs_merge() {
+        assert_eq!(to_runs(&[4, 3, 1, 2, 2]), vec![(1, 4)]);
+        assert_eq!(to_runs(&[5, 1, 2, 3, 7]), vec![(1, 3), (5, 5), (7, 7)]);
+        assert_eq!(round_trip(&[5, 1, 2, 3, 7]), vec![1, 2, 3, 5, 7]);
+    }
+
+    #[test]
+    fn runs_reach_the_largest_value() {
+        assert_eq!(
+            to_runs(&[u64::MAX, u64::MAX - 1, 0]),
+            vec![(0, 0), (u64::MAX - 1, u64::MAX)]
+        );
+        assert_eq!(round_trip(&[u64::MAX, 0]), vec![0, u64::MAX]);
+    }
+
+    #[test]
+    fn backwards_runs_are_rejected() {
+        assert_eq!(from_runs(&[(1, 2), (5, 4)]), None);
+    }
+
+    #[test]
+    fn runs_beyond_the_cap_are_rejected() {
+        assert_eq!(from_runs(&[(0, u64::MAX)]), None);
+        assert_eq!(from_runs(&[(0, MAX_EXPANDED)]), None);
+        assert_eq!(
+            from_runs(&[(0, MAX_EXPANDED / 2), (MAX_EXPANDED, MAX_EXPANDED * 2)]),
+            None
+        );
+        assert_eq!(
+            from_runs(&[(10, MAX_EXPANDED + 9)]).map(|values| values.len() as u64),
+            Some(MAX_EXPANDED)
+        );
+    }
+}
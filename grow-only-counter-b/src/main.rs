@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use server::{Message, Sender};
+
+mod server;
+
+/// How often every node sends its counts to every other node
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum P {
+    Add {
+        delta: u64,
+    },
+    AddOk {},
+    Read {},
+    ReadOk {
+        value: u64,
+    },
+    /// Every count the sender knows of
+    Merge {
+        counts: HashMap<String, u64>,
+    },
+}
+
+/// A G-counter: each node only ever increments its own count, so counts from other nodes can be
+/// merged in any order, any number of times, by taking the larger of each. The total is the sum.
+struct Context {
+    sender: Sender,
+    counts: HashMap<String, u64>,
+}
+
+impl Context {
+    fn merge(&mut self, counts: HashMap<String, u64>) {
+        for (node, count) in counts {
+            let ours = self.counts.entry(node).or_default();
+            *ours = (*ours).max(count);
+        }
+    }
+}
+
+fn main() -> serde_json::Result<()> {
+    let (server, sender) = server::init()?;
+    let context: Arc<Mutex<Context>> = Arc::new(Mutex::new(Context {
+        sender,
+        counts: HashMap::new(),
+    }));
+    let thread_context = context.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(GOSSIP_INTERVAL);
+        let mut ctx = thread_context.lock().unwrap();
+        let ctx = ctx.deref_mut();
+        // Gossip the whole map every time, so that nothing is lost to a partition for longer
+        // than the partition lasts
+        let peers: Vec<String> = ctx
+            .sender
+            .node_ids
+            .iter()
+            .filter(|n| **n != ctx.sender.node_id)
+            .cloned()
+            .collect();
+        for peer in peers {
+            let counts = ctx.counts.clone();
+            ctx.sender
+                .send(&peer, &P::Merge { counts })
+                .expect("Error sending gossip");
+        }
+    });
+    loop {
+        let message: Message<P> = server.read_message()?;
+        let mut ctx = context.lock().unwrap();
+        let ctx = ctx.deref_mut();
+        match message.body.fields {
+            P::Add { delta } => {
+                *ctx.counts.entry(ctx.sender.node_id.clone()).or_default() += delta;
+                ctx.sender.respond(&message, &P::AddOk {})?
+            }
+            P::Read {} => {
+                let value = ctx.counts.values().sum();
+                ctx.sender.respond(&message, &P::ReadOk { value })?
+            }
+            P::Merge { counts } => ctx.merge(counts),
+            _ => panic!("NOT ALLOWED"),
+        }
+    }
+}
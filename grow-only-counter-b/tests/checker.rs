@@ -0,0 +1,26 @@
+//! Checks that every node finally reads the sum of the acknowledged adds, even when one node was
+//! partitioned off while the adds happened.
+mod common;
+
+use common::{Cluster, NODES};
+
+#[test]
+fn final_reads_equal_acknowledged_adds() {
+    let cluster = Cluster::start(&[]);
+    cluster.partitioned.lock().unwrap().push("n2".to_string());
+    let mut total = 0;
+    let mut partitioned_total = 0;
+    for i in 0..60_i64 {
+        let delta = i % 5;
+        let node = NODES[i as usize % NODES.len()];
+        assert!(cluster.add(node, delta));
+        total += delta;
+        if node == "n2" {
+            partitioned_total += delta;
+        }
+    }
+    // The partitioned node only knows of its own adds
+    assert_eq!(cluster.read("n2"), partitioned_total);
+    cluster.partitioned.lock().unwrap().clear();
+    cluster.converges(total);
+}
@@ -0,0 +1,434 @@
+//! Adding to a single shared key with read and compare-and-set, as a state machine.
+//!
+//! The machine only decides what to ask the key-value store next; sending the requests and
+//! matching up the replies is left to the caller. At most one request is outstanding at a time,
+//! and the caller must only pass on replies to that one, so a late reply to a request given up
+//! on can never be applied in its place. Deltas that arrive while a CAS is in flight are
+//! coalesced into the next one. A failed CAS retries after a jittered exponential backoff, so
+//! that nodes that collided do not collide again in lockstep.
+//!
+//! The key does not hold the count itself but a Tally of every node's running total, and a CAS
+//! only ever raises this node's entry to everything it has added so far. A CAS that lands twice,
+//! or late, sets the entry to a total it already had, so no delta is ever counted twice. A
+//! request that goes unanswered for REQUEST_TIMEOUT is given up on, and the read that follows
+//! shows exactly how much of this node's adds landed, whatever other nodes added on top since.
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::REQUEST_TIMEOUT;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// What a shared key holds: each node's running total, by node. The count is their sum.
+pub type Tally = BTreeMap<String, u64>;
+
+/// What the caller should send to the store, for keys holding a `T`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action<T = u64> {
+    Read,
+    Cas { from: T, to: T },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum State {
+    /// Nothing outstanding
+    Idle,
+    /// Waiting for a read sent at `since`
+    Reading { since: Instant },
+    /// Waiting for a CAS to `to`, sent at `since`
+    Casing { to: Tally, since: Instant },
+    /// A request failed; read again once `until` has passed
+    Backoff { until: Instant },
+}
+
+pub struct CasCounter {
+    /// Our entry in the tally
+    node_id: String,
+    state: State,
+    /// Every delta ever added, in total
+    added: u64,
+    /// Our highest entry seen in the store. Each CAS carries every delta added before it, so an
+    /// add has landed once this reaches what `added` was after it.
+    written: u64,
+    /// The latest tally seen in the store
+    tally: Tally,
+    /// Consecutive failed requests
+    failures: u32,
+    /// When `tally` was last known to be the one in the store
+    confirmed: Option<Instant>,
+    rng: u64,
+}
+
+impl CasCounter {
+    /// `seed` only drives the backoff jitter, and should differ between nodes
+    pub fn new(node_id: &str, seed: u64) -> CasCounter {
+        CasCounter {
+            node_id: node_id.to_string(),
+            state: State::Idle,
+            added: 0,
+            written: 0,
+            tally: Tally::new(),
+            failures: 0,
+            confirmed: None,
+            rng: seed | 1,
+        }
+    }
+    /// The latest value seen in the store, which can lag behind other nodes' adds
+    pub fn value(&self) -> u64 {
+        self.tally.values().sum()
+    }
+    /// Deltas added that are not part of `value` yet
+    pub fn unwritten(&self) -> u64 {
+        self.added.saturating_sub(self.ours(&self.tally))
+    }
+    pub fn added(&self) -> u64 {
+        self.added
+    }
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+    /// How long ago `value` was known to be current, if it ever was
+    pub fn age(&self) -> Option<Duration> {
+        self.confirmed.map(|confirmed| confirmed.elapsed())
+    }
+    pub fn add(&mut self, delta: u64) -> Option<Action<Tally>> {
+        self.added += delta;
+        match self.state {
+            State::Idle => self.cas(),
+            _ => None,
+        }
+    }
+    /// Read the store again so that other nodes' adds show up, unless busy anyway
+    pub fn refresh(&mut self) -> Option<Action<Tally>> {
+        match self.state {
+            State::Idle => Some(self.read()),
+            _ => None,
+        }
+    }
+    /// The store answered a read. A key that does not exist yet reads as an empty tally.
+    pub fn read_ok(&mut self, tally: Tally) -> Option<Action<Tally>> {
+        let State::Reading { .. } = self.state else {
+            return None;
+        };
+        self.seen(tally);
+        self.state = State::Idle;
+        self.cas()
+    }
+    pub fn cas_ok(&mut self) -> Option<Action<Tally>> {
+        let State::Casing { to, .. } = &self.state else {
+            return None;
+        };
+        self.seen(to.clone());
+        self.state = State::Idle;
+        self.failures = 0;
+        self.cas()
+    }
+    /// The CAS precondition failed, someone else got there first. Returns how long to wait
+    /// before calling tick.
+    pub fn cas_failed(&mut self, now: Instant) -> Option<Duration> {
+        match self.state {
+            State::Casing { .. } => self.failed(now),
+            _ => None,
+        }
+    }
+    /// The outstanding request failed. Returns how long to wait before calling tick.
+    pub fn failed(&mut self, now: Instant) -> Option<Duration> {
+        let (State::Casing { .. } | State::Reading { .. }) = self.state else {
+            return None;
+        };
+        self.failures += 1;
+        let backoff = self.backoff();
+        self.state = State::Backoff {
+            until: now + backoff,
+        };
+        Some(backoff)
+    }
+    /// The outstanding request may or may not have changed the key, because it went unanswered
+    /// or the store said so. Read the key to find out.
+    pub fn timed_out(&mut self) -> Option<Action<Tally>> {
+        match self.state {
+            State::Reading { .. } | State::Casing { .. } => Some(self.read()),
+            _ => None,
+        }
+    }
+    /// Leave backoff once it has passed, and give up on a request that has gone unanswered for
+    /// REQUEST_TIMEOUT
+    pub fn tick(&mut self, now: Instant) -> Option<Action<Tally>> {
+        match self.state {
+            State::Backoff { until } if now >= until => Some(self.read()),
+            State::Reading { since } | State::Casing { since, .. }
+                if now >= since + REQUEST_TIMEOUT =>
+            {
+                self.timed_out()
+            }
+            _ => None,
+        }
+    }
+    fn ours(&self, tally: &Tally) -> u64 {
+        tally.get(&self.node_id).copied().unwrap_or(0)
+    }
+    /// The store held `tally`
+    fn seen(&mut self, tally: Tally) {
+        self.written = self.written.max(self.ours(&tally));
+        self.tally = tally;
+        self.confirmed = Some(Instant::now());
+    }
+    fn read(&mut self) -> Action<Tally> {
+        self.state = State::Reading {
+            since: Instant::now(),
+        };
+        Action::Read
+    }
+    /// Start a CAS raising our entry to everything added, if some of it has not landed
+    fn cas(&mut self) -> Option<Action<Tally>> {
+        if self.added <= self.written {
+            return None;
+        }
+        let from = self.tally.clone();
+        let mut to = from.clone();
+        to.insert(self.node_id.clone(), self.added);
+        self.state = State::Casing {
+            to: to.clone(),
+            since: Instant::now(),
+        };
+        Some(Action::Cas { from, to })
+    }
+    /// Exponential in the number of failures, starting from MIN_BACKOFF, scaled by a random
+    /// factor between a half and one
+    fn backoff(&mut self) -> Duration {
+        let ceiling = MIN_BACKOFF
+            .saturating_mul(1 << self.failures.saturating_sub(1).min(16))
+            .min(MAX_BACKOFF);
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let jitter = 0.5 + (self.rng % 1000) as f64 / 2000.0;
+        ceiling.mul_f64(jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tally(entries: &[(&str, u64)]) -> Tally {
+        entries
+            .iter()
+            .map(|(node, total)| (node.to_string(), *total))
+            .collect()
+    }
+
+    fn cas(from: &[(&str, u64)], to: &[(&str, u64)]) -> Option<Action<Tally>> {
+        Some(Action::Cas {
+            from: tally(from),
+            to: tally(to),
+        })
+    }
+
+    fn counter() -> CasCounter {
+        CasCounter::new("n1", 1)
+    }
+
+    #[test]
+    fn add_when_idle_cases_immediately() {
+        let mut counter = counter();
+        assert_eq!(counter.add(3), cas(&[], &[("n1", 3)]));
+        assert!(matches!(counter.state, State::Casing { .. }));
+    }
+
+    #[test]
+    fn adds_during_a_cas_are_coalesced() {
+        let mut counter = counter();
+        counter.add(3);
+        assert_eq!(counter.add(4), None);
+        assert_eq!(counter.add(5), None);
+        assert_eq!(counter.cas_ok(), cas(&[("n1", 3)], &[("n1", 12)]));
+        // Only the first add has landed so far
+        assert_eq!((counter.written(), counter.added()), (3, 12));
+        assert_eq!(counter.cas_ok(), None);
+        assert_eq!(counter.written(), 12);
+        assert_eq!(counter.state, State::Idle);
+        assert_eq!(counter.value(), 12);
+    }
+
+    #[test]
+    fn failed_cas_backs_off_then_rereads_and_retries() {
+        let mut counter = counter();
+        let now = Instant::now();
+        counter.add(3);
+        let backoff = counter.cas_failed(now).unwrap();
+        assert!(backoff >= MIN_BACKOFF.mul_f64(0.5) && backoff <= MIN_BACKOFF);
+        // Adds during backoff wait for the retry
+        assert_eq!(counter.add(1), None);
+        assert_eq!(counter.tick(now), None);
+        assert_eq!(counter.tick(now + backoff), Some(Action::Read));
+        assert_eq!(
+            counter.read_ok(tally(&[("n2", 10)])),
+            cas(&[("n2", 10)], &[("n1", 4), ("n2", 10)])
+        );
+        assert_eq!(counter.cas_ok(), None);
+        assert_eq!(counter.value(), 14);
+    }
+
+    #[test]
+    fn backoff_grows_with_repeated_failures() {
+        let mut counter = CasCounter::new("n1", 7);
+        let now = Instant::now();
+        let mut ceilings = vec![];
+        counter.add(1);
+        for _ in 0..12 {
+            let backoff = counter.cas_failed(now).unwrap();
+            ceilings.push(backoff);
+            counter.tick(now + backoff);
+            counter.read_ok(Tally::new());
+        }
+        assert!(ceilings[3] > ceilings[0]);
+        assert!(ceilings.iter().all(|backoff| *backoff <= MAX_BACKOFF));
+    }
+
+    #[test]
+    fn success_resets_backoff() {
+        let mut counter = counter();
+        let now = Instant::now();
+        counter.add(1);
+        for _ in 0..5 {
+            let backoff = counter.cas_failed(now).unwrap();
+            counter.tick(now + backoff);
+            counter.read_ok(Tally::new());
+        }
+        counter.cas_ok();
+        counter.add(1);
+        assert!(counter.cas_failed(now).unwrap() <= MIN_BACKOFF);
+    }
+
+    #[test]
+    fn refresh_only_reads_when_idle() {
+        let mut counter = counter();
+        assert_eq!(counter.refresh(), Some(Action::Read));
+        assert_eq!(counter.refresh(), None);
+        assert_eq!(counter.read_ok(tally(&[("n2", 5)])), None);
+        assert_eq!(counter.value(), 5);
+        counter.add(1);
+        assert_eq!(counter.refresh(), None);
+    }
+
+    #[test]
+    fn unwritten_deltas_are_tracked_until_their_cas_succeeds() {
+        let mut counter = counter();
+        assert_eq!(counter.written(), counter.added());
+        assert_eq!(counter.age(), None);
+        counter.add(3);
+        counter.add(4);
+        assert_eq!(counter.unwritten(), 7);
+        counter.cas_failed(Instant::now());
+        assert_eq!(counter.unwritten(), 3 + 4);
+        assert!(counter.written() < counter.added());
+        counter.tick(Instant::now() + MAX_BACKOFF);
+        counter.read_ok(tally(&[("n2", 10)]));
+        counter.cas_ok();
+        assert_eq!(counter.written(), counter.added());
+        assert_eq!(counter.value(), 17);
+        assert!(counter.age().is_some());
+    }
+
+    #[test]
+    fn unexpected_replies_are_ignored() {
+        let mut counter = counter();
+        assert_eq!(counter.cas_ok(), None);
+        assert_eq!(counter.cas_failed(Instant::now()), None);
+        assert_eq!(counter.read_ok(tally(&[("n2", 100)])), None);
+        assert_eq!(counter.value(), 0);
+        counter.add(2);
+        // A read reply while a CAS is outstanding does not start a second CAS
+        assert_eq!(counter.read_ok(tally(&[("n2", 100)])), None);
+        assert_eq!(counter.cas_ok(), None);
+        assert_eq!(counter.value(), 2);
+    }
+
+    #[test]
+    fn unanswered_reads_are_sent_again() {
+        let mut counter = counter();
+        let now = Instant::now();
+        assert_eq!(counter.refresh(), Some(Action::Read));
+        assert_eq!(counter.tick(now), None);
+        assert_eq!(counter.tick(now + REQUEST_TIMEOUT * 2), Some(Action::Read));
+        assert_eq!(counter.read_ok(tally(&[("n2", 4)])), None);
+        assert_eq!(counter.value(), 4);
+    }
+
+    #[test]
+    fn timed_out_cas_that_landed_is_not_added_again() {
+        let mut counter = counter();
+        let now = Instant::now();
+        counter.add(3);
+        assert_eq!(counter.tick(now + REQUEST_TIMEOUT * 2), Some(Action::Read));
+        assert!(counter.written() < counter.added());
+        assert_eq!(counter.read_ok(tally(&[("n1", 3)])), None);
+        assert_eq!(counter.written(), counter.added());
+        assert_eq!(counter.value(), 3);
+    }
+
+    #[test]
+    fn timed_out_cas_that_landed_under_another_nodes_add_is_not_added_again() {
+        let mut counter = counter();
+        let now = Instant::now();
+        counter.add(3);
+        counter.tick(now + REQUEST_TIMEOUT * 2);
+        // The CAS landed, and another node added on top before the read
+        assert_eq!(counter.read_ok(tally(&[("n1", 3), ("n2", 5)])), None);
+        assert_eq!(counter.written(), counter.added());
+        assert_eq!(counter.value(), 8);
+        assert_eq!(
+            counter.add(4),
+            cas(&[("n1", 3), ("n2", 5)], &[("n1", 7), ("n2", 5)])
+        );
+    }
+
+    #[test]
+    fn timed_out_cas_that_did_not_land_is_retried() {
+        let mut counter = counter();
+        let now = Instant::now();
+        counter.add(3);
+        counter.tick(now + REQUEST_TIMEOUT * 2);
+        assert_eq!(
+            counter.read_ok(tally(&[("n2", 5)])),
+            cas(&[("n2", 5)], &[("n1", 3), ("n2", 5)])
+        );
+    }
+
+    #[test]
+    fn a_cas_retried_after_the_first_landed_late_counts_once() {
+        let mut counter = counter();
+        let now = Instant::now();
+        let Some(Action::Cas { from, to: first }) = counter.add(3) else {
+            panic!("Expected a CAS");
+        };
+        counter.tick(now + REQUEST_TIMEOUT * 2);
+        // The read overtakes the first CAS, so it looks like the CAS did not land
+        let retry = counter.read_ok(from.clone());
+        assert_eq!(retry, cas(&[], &[("n1", 3)]));
+        // It lands after all, so the retry fails, and the read after finds the delta in
+        assert_eq!(first, tally(&[("n1", 3)]));
+        let backoff = counter.cas_failed(now).unwrap();
+        counter.tick(now + backoff);
+        assert_eq!(counter.read_ok(first), None);
+        assert_eq!(counter.value(), 3);
+        assert_eq!(counter.written(), counter.added());
+    }
+
+    #[test]
+    fn failed_reads_back_off_and_read_again() {
+        let mut counter = counter();
+        let now = Instant::now();
+        counter.add(3);
+        assert_eq!(counter.timed_out(), Some(Action::Read));
+        let backoff = counter.failed(now).unwrap();
+        assert_eq!(counter.unwritten(), 3);
+        assert_eq!(counter.tick(now + backoff), Some(Action::Read));
+        assert_eq!(counter.read_ok(tally(&[("n1", 3)])), None);
+        assert_eq!(counter.value(), 3);
+        assert_eq!(counter.written(), counter.added());
+    }
+}
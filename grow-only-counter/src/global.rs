@@ -0,0 +1,376 @@
+//! The original design: every node adds to one key per counter, using cas::CasCounter. Simpler
+//! than sharding, but nodes contend for the key under load.
+//!
+//! Reads are answered from the last value seen unless the client asks for a strict read, which
+//! waits for the adds this node had taken when the read arrived to land, and then reads the key
+//! again. A plain read is linearizable in lin-kv, but seq-kv may serve stale values to a plain
+//! read, so there a unique write goes first to order the read after it. In strict mode adds are
+//! only acknowledged once they have landed, so a strict read sees every add acknowledged before
+//! it arrived, on any node. Otherwise adds are acknowledged straight away, and a strict read is
+//! only as fresh as what other nodes have written.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::cas::{Action, CasCounter, Tally};
+use crate::server::{Message, Sender, Server};
+use crate::{
+    sync_key, ReadMode, DEFAULT_COUNTER, KEY_DOES_NOT_EXIST, P, PRECONDITION_FAILED,
+    REQUEST_TIMEOUT, TICK_INTERVAL,
+};
+
+/// The store's error codes for a request that may or may not have taken effect
+const INDEFINITE: [u64; 2] = [0, 13];
+/// How often to reread the key so that other nodes' adds show up
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A strict client read waiting on the store
+struct StrictRead {
+    request: Message<P>,
+    counter: String,
+    /// The counter's CasCounter::added when the read arrived
+    after: u64,
+    /// When the read last sent something to the store
+    sent: Instant,
+}
+
+/// The request each counter is waiting on, by the counter's name. Only the latest one counts: a
+/// request given up on may still be answered, and its reply must not be taken for the one that
+/// replaced it.
+#[derive(Default)]
+struct InFlight(HashMap<String, u64>);
+
+impl InFlight {
+    fn sent(&mut self, name: &str, msg_id: u64) {
+        self.0.insert(name.to_string(), msg_id);
+    }
+    /// The counter a reply is for, if it answers the request the counter is waiting on
+    fn answered(&mut self, msg_id: u64) -> Option<String> {
+        let name = self.0.iter().find(|(_, id)| **id == msg_id)?.0.clone();
+        self.0.remove(&name);
+        Some(name)
+    }
+}
+
+/// The key a counter is stored under, kept apart from the keys strict reads sync with
+fn counter_key(name: &str) -> String {
+    format!("counter/{}", name)
+}
+
+struct Context {
+    sender: Sender,
+    service: String,
+    /// Whether to hold back acknowledging adds until they have landed, and read strictly by
+    /// default
+    strict: bool,
+    /// Each counter, stored under counter_key
+    counters: HashMap<String, CasCounter>,
+    in_flight: InFlight,
+    /// Adds waiting to land before they are acknowledged, with the counter's CasCounter::added
+    /// after each
+    unacked: Vec<(String, u64, Message<P>)>,
+    /// Strict reads waiting for this node's earlier adds to their counter to be written
+    flushing: Vec<StrictRead>,
+    /// Strict reads waiting for their unique write, by the write's msg_id
+    syncing: HashMap<u64, StrictRead>,
+    /// Strict reads waiting for the key, by the read's msg_id
+    reading: HashMap<u64, StrictRead>,
+}
+
+impl Context {
+    fn counter(&mut self, name: &str) -> &mut CasCounter {
+        let node_id = &self.sender.node_id;
+        self.counters.entry(name.to_string()).or_insert_with(|| {
+            let mut hasher = DefaultHasher::new();
+            (node_id, name).hash(&mut hasher);
+            CasCounter::new(node_id, hasher.finish())
+        })
+    }
+    fn perform(&mut self, name: &str, action: Option<Action<Tally>>) -> serde_json::Result<()> {
+        let Some(action) = action else {
+            return Ok(());
+        };
+        let request = match action {
+            Action::Read => P::Read {
+                key: Some(counter_key(name)),
+                mode: None,
+            },
+            Action::Cas { from, to } => P::Cas {
+                key: counter_key(name),
+                from: serde_json::to_value(from)?,
+                to: serde_json::to_value(to)?,
+                create_if_not_exists: true,
+            },
+        };
+        let message = self.sender.message(&self.service, request)?;
+        self.in_flight
+            .sent(name, message.body.msg_id.expect("No msg_id???"));
+        self.sender.send_message(&message)
+    }
+    fn written(&self, counter: &str, added: u64) -> bool {
+        self.counters[counter].written() >= added
+    }
+    /// Acknowledge the adds that have landed, and start the strict reads whose earlier adds
+    /// have. Reading while a CAS is in flight could not tell whether its delta was in the value
+    /// or not, but adds that arrive after a read started do not hold it up.
+    fn written_through(&mut self) -> serde_json::Result<()> {
+        let (written, unacked) = std::mem::take(&mut self.unacked)
+            .into_iter()
+            .partition(|(counter, added, _)| self.written(counter, *added));
+        self.unacked = unacked;
+        for (_, _, add) in written {
+            self.sender.respond(&add, P::AddOk {})?;
+        }
+        let (ready, waiting): (Vec<StrictRead>, Vec<StrictRead>) =
+            std::mem::take(&mut self.flushing)
+                .into_iter()
+                .partition(|read| self.written(&read.counter, read.after));
+        self.flushing = waiting;
+        for read in ready {
+            self.strict_read(read)?;
+        }
+        Ok(())
+    }
+    /// Start over the strict reads the store has not answered within REQUEST_TIMEOUT
+    fn retry_reads(&mut self) -> serde_json::Result<()> {
+        let Some(cutoff) = Instant::now().checked_sub(REQUEST_TIMEOUT) else {
+            return Ok(());
+        };
+        let lost: Vec<u64> = self
+            .syncing
+            .iter()
+            .chain(&self.reading)
+            .filter(|(_, read)| read.sent < cutoff)
+            .map(|(msg_id, _)| *msg_id)
+            .collect();
+        for msg_id in lost {
+            let read = self.syncing.remove(&msg_id);
+            if let Some(read) = read.or_else(|| self.reading.remove(&msg_id)) {
+                self.strict_read(read)?;
+            }
+        }
+        Ok(())
+    }
+    /// Start a strict read, syncing first unless the store is linearizable anyway
+    fn strict_read(&mut self, mut read: StrictRead) -> serde_json::Result<()> {
+        read.sent = Instant::now();
+        if self.service == "lin-kv" {
+            return self.read_key(read);
+        }
+        let sync = self.sender.message(
+            &self.service,
+            P::Write {
+                key: sync_key(&self.sender.node_id),
+                value: read.request.body.msg_id.unwrap_or_default(),
+            },
+        )?;
+        self.syncing
+            .insert(sync.body.msg_id.expect("No msg_id???"), read);
+        self.sender.send_message(&sync)
+    }
+    fn read_key(&mut self, read: StrictRead) -> serde_json::Result<()> {
+        let message = self.sender.message(
+            &self.service,
+            P::Read {
+                key: Some(counter_key(&read.counter)),
+                mode: None,
+            },
+        )?;
+        self.reading
+            .insert(message.body.msg_id.expect("No msg_id???"), read);
+        self.sender.send_message(&message)
+    }
+    fn strict_read_ok(&mut self, read: StrictRead, tally: Tally) -> serde_json::Result<()> {
+        let value = tally.values().sum();
+        self.sender.respond(&read.request, P::strict_read_ok(value))
+    }
+}
+
+pub fn run(
+    server: Server,
+    sender: Sender,
+    service: String,
+    strict: bool,
+) -> serde_json::Result<()> {
+    let context: Arc<Mutex<Context>> = Arc::new(Mutex::new(Context {
+        sender,
+        service,
+        strict,
+        unacked: vec![],
+        counters: HashMap::new(),
+        in_flight: InFlight::default(),
+        flushing: vec![],
+        syncing: HashMap::new(),
+        reading: HashMap::new(),
+    }));
+    let thread_context = context.clone();
+    std::thread::spawn(move || {
+        let mut last_refresh = Instant::now();
+        loop {
+            std::thread::sleep(TICK_INTERVAL);
+            let mut ctx = thread_context.lock().unwrap();
+            let refresh = last_refresh.elapsed() >= REFRESH_INTERVAL;
+            if refresh {
+                last_refresh = Instant::now();
+            }
+            let names: Vec<String> = ctx.counters.keys().cloned().collect();
+            for name in names {
+                let counter = ctx.counter(&name);
+                let mut action = counter.tick(Instant::now());
+                if action.is_none() && refresh {
+                    action = counter.refresh();
+                }
+                ctx.perform(&name, action)
+                    .expect("Error sending to the store");
+            }
+            ctx.written_through().expect("Error sending to the store");
+            ctx.retry_reads().expect("Error sending to the store");
+        }
+    });
+    loop {
+        let message: Message<P> = server.read_message()?;
+        let mut ctx = context.lock().unwrap();
+        let ctx = ctx.deref_mut();
+        let in_reply_to = message.body.in_reply_to.unwrap_or_default();
+        if let Some(read) = ctx.syncing.remove(&in_reply_to) {
+            ctx.read_key(read)?;
+            continue;
+        }
+        if let Some(read) = ctx.reading.remove(&in_reply_to) {
+            match message.body.fields {
+                P::ReadOk { value, .. } => {
+                    ctx.strict_read_ok(read, serde_json::from_value(value)?)?
+                }
+                P::Error { code, .. } if code == KEY_DOES_NOT_EXIST => {
+                    ctx.strict_read_ok(read, Tally::new())?
+                }
+                // Anything else, say the store being temporarily unavailable, is retried
+                P::Error { .. } => ctx.strict_read(read)?,
+                _ => panic!("NOT ALLOWED"),
+            }
+            continue;
+        }
+        // Replies to anything but the request a counter is waiting on are stale
+        let replying_for = message
+            .body
+            .in_reply_to
+            .and_then(|msg_id| ctx.in_flight.answered(msg_id));
+        let (name, action) = match (&message.body.fields, replying_for) {
+            (P::Add { key, delta }, _) => {
+                let name = key.as_deref().unwrap_or(DEFAULT_COUNTER).to_string();
+                let counter = ctx.counter(&name);
+                let action = counter.add(*delta);
+                let added = counter.added();
+                if ctx.strict {
+                    ctx.unacked.push((name.clone(), added, message.clone()));
+                } else {
+                    ctx.sender.respond(&message, &P::AddOk {})?;
+                }
+                (name, action)
+            }
+            // Only clients send us reads
+            (P::Read { key, mode }, _) => {
+                let name = key.as_deref().unwrap_or(DEFAULT_COUNTER).to_string();
+                let known = ctx.counters.contains_key(&name);
+                let default = if ctx.strict {
+                    ReadMode::Strict
+                } else {
+                    ReadMode::Cached
+                };
+                if mode.unwrap_or(default) == ReadMode::Strict {
+                    let after = ctx.counter(&name).added();
+                    ctx.flushing.push(StrictRead {
+                        request: message.clone(),
+                        counter: name,
+                        after,
+                        sent: Instant::now(),
+                    });
+                    ctx.written_through()?;
+                    continue;
+                }
+                let counter = ctx.counter(&name);
+                let read_ok = P::ReadOk {
+                    value: (counter.value() + counter.unwritten()).into(),
+                    mode: Some(ReadMode::Cached),
+                    staleness_ms: counter.age().map(|age| age.as_millis() as u64),
+                };
+                ctx.sender.respond(&message, &read_ok)?;
+                // Fetch counters we have not heard of yet, instead of waiting for the refresh
+                if known {
+                    continue;
+                }
+                let action = ctx.counter(&name).refresh();
+                (name, action)
+            }
+            (_, None) => continue,
+            (P::ReadOk { value, .. }, Some(name)) => {
+                let tally = serde_json::from_value(value.clone())?;
+                let action = ctx.counter(&name).read_ok(tally);
+                (name, action)
+            }
+            (P::CasOk {}, Some(name)) => {
+                let action = ctx.counter(&name).cas_ok();
+                (name, action)
+            }
+            (P::Error { code, .. }, Some(name)) if *code == KEY_DOES_NOT_EXIST => {
+                let action = ctx.counter(&name).read_ok(Tally::new());
+                (name, action)
+            }
+            (P::Error { code, .. }, Some(name)) if *code == PRECONDITION_FAILED => {
+                ctx.counter(&name).cas_failed(Instant::now());
+                continue;
+            }
+            (P::Error { code, .. }, Some(name)) if INDEFINITE.contains(code) => {
+                let action = ctx.counter(&name).timed_out();
+                (name, action)
+            }
+            // Anything else, say the store being temporarily unavailable, is retried
+            (P::Error { .. }, Some(name)) => {
+                ctx.counter(&name).failed(Instant::now());
+                continue;
+            }
+            _ => panic!("NOT ALLOWED"),
+        };
+        ctx.perform(&name, action)?;
+        ctx.written_through()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_to_requests_given_up_on_are_dropped() {
+        let mut in_flight = InFlight::default();
+        let mut counter = CasCounter::new("n1", 1);
+        assert!(matches!(counter.add(3), Some(Action::Cas { .. })));
+        in_flight.sent("a", 1);
+        // The CAS goes unanswered, so the counter reads the key to find out whether it landed
+        let action = counter.tick(Instant::now() + REQUEST_TIMEOUT);
+        assert_eq!(action, Some(Action::Read));
+        in_flight.sent("a", 2);
+        assert_eq!(in_flight.answered(2).as_deref(), Some("a"));
+        let landed = Tally::from([("n1".to_string(), 3)]);
+        assert_eq!(counter.read_ok(landed), None);
+        assert_eq!(counter.written(), 3);
+        assert!(matches!(counter.add(4), Some(Action::Cas { .. })));
+        in_flight.sent("a", 3);
+        // The first CAS's reply finally arrives, and must not be taken for the second's
+        assert_eq!(in_flight.answered(1), None);
+        assert_eq!(counter.written(), 3);
+        assert_eq!(in_flight.answered(3).as_deref(), Some("a"));
+        assert_eq!(counter.cas_ok(), None);
+        assert_eq!(counter.written(), 7);
+        assert_eq!(in_flight.answered(3), None);
+    }
+
+    #[test]
+    fn counter_keys_never_collide_with_sync_keys() {
+        assert_ne!(counter_key("sync-n1"), sync_key("n1"));
+        assert_ne!(counter_key("sync/n1"), sync_key("n1"));
+    }
+}
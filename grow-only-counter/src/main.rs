@@ -1,35 +1,79 @@
-use std::collections::HashMap;
-use std::ops::DerefMut;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use server::{Message, Sender};
+use shard::Shard;
 
+mod cas;
+mod global;
 mod server;
+mod shard;
 
-#[derive(Serialize, Deserialize)]
+/// Environment variable selecting where the count is kept: "sharded" (the default) gives every
+/// node its own key, "global" has every node add to one key, see global.rs
+const STORE_VAR: &str = "GCOUNTER_STORE";
+
+/// Environment variable selecting the key-value service counters are kept in, "seq-kv" (the
+/// default) or "lin-kv"
+const SERVICE_VAR: &str = "GCOUNTER_SERVICE";
+
+/// Environment variable that, set to "strict", only acknowledges adds once they are in the
+/// store, so that a strict read on any node sees them, and makes reads strict by default
+const MODE_VAR: &str = "GCOUNTER_MODE";
+
+/// The store's error code for reading a key that has never been written
+const KEY_DOES_NOT_EXIST: u64 = 20;
+/// The store's error code for a CAS whose `from` did not match
+const PRECONDITION_FAILED: u64 = 22;
+/// The counter that adds and reads without a key go to
+const DEFAULT_COUNTER: &str = "global";
+/// How often to look for backoffs that have passed and requests to the store that have gone
+/// unanswered
+const TICK_INTERVAL: Duration = Duration::from_millis(5);
+/// How long to wait for the store before assuming a request or its reply was lost, and giving up
+/// on it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum P {
     Add {
+        /// The counter's name, or the default counter if absent
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
         delta: u64,
     },
     AddOk {},
+    /// From a client, a read of a counter. To the store, a read of a key.
     Read {
         key: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<ReadMode>,
     },
+    /// The value is a number, except from a key holding a cas::Tally
     ReadOk {
-        value: u64,
+        value: Value,
+        /// How the value was read, only set in replies to clients
+        #[serde(skip_serializing_if = "Option::is_none")]
+        mode: Option<ReadMode>,
+        /// How long ago the value was known to be current, if it was not read just now
+        #[serde(skip_serializing_if = "Option::is_none")]
+        staleness_ms: Option<u64>,
     },
     Write {
         key: String,
         value: u64,
     },
     WriteOk {},
+    /// Of a number in a shard's key, or of a cas::Tally in a shared one
     Cas {
         key: String,
-        from: u64,
-        to: u64,
+        from: Value,
+        to: Value,
         create_if_not_exists: bool,
     },
     CasOk {},
@@ -39,118 +83,329 @@ enum P {
     },
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum ReadMode {
+    /// Answer from what the node last saw, which is cheap but can be stale
+    #[default]
+    Cached,
+    /// Answer with a value read from the store after the read arrived
+    Strict,
+}
+
+impl P {
+    fn strict_read_ok(value: u64) -> P {
+        P::ReadOk {
+            value: value.into(),
+            mode: Some(ReadMode::Strict),
+            staleness_ms: None,
+        }
+    }
+}
+
+/// A client read waiting on the store
+struct PendingRead {
+    request: Message<P>,
+    counter: String,
+    /// When the current attempt at the read started
+    started: Instant,
+    /// Nodes whose keys have not been read yet
+    remaining: HashSet<String>,
+    sum: u64,
+}
+
+/// Every node only ever writes its own total for each counter, to a key named after the counter
+/// and the node, so writes never contend with other nodes, see shard.rs. Reads sum every node's
+/// key, and so are always strict. Requests to the store that go unanswered are sent again, so a
+/// lost message can delay a total or a read but never strand it.
 struct Context {
     sender: Sender,
-    delta: u64,
-    last_global: u64,
-    pendings: HashMap<u64, (u64, u64)>,
+    service: String,
+    /// Whether to hold back acknowledging adds until their total is written
+    strict: bool,
+    shards: HashMap<String, Shard>,
+    /// Adds waiting for their total to be written before they are acknowledged, with the total
+    /// after each
+    unacked: Vec<(String, u64, Message<P>)>,
+    /// Which counter each shard's outstanding request is for, by msg_id. Only the latest request
+    /// per counter is kept, so that replies to ones given up on are dropped.
+    writes: HashMap<u64, String>,
+    /// Reads waiting for their sync write to be acknowledged, by the write's msg_id
+    syncing: HashMap<u64, PendingRead>,
+    /// Reads waiting for node keys, by the sync write's msg_id, or on lin-kv by an id from
+    /// next_read
+    reading: HashMap<u64, PendingRead>,
+    next_read: u64,
+    /// Which read and node each outstanding key read is for
+    key_reads: HashMap<u64, (u64, String)>,
+}
+
+/// The key holding a node's total for a counter
+fn shard_key(counter: &str, node: &str) -> String {
+    format!("counter/{}/{}", counter, node)
+}
+
+/// The key a node writes to order a read after what the store has acknowledged. Counter keys
+/// all start with "counter/", so no counter name can collide with it.
+fn sync_key(node: &str) -> String {
+    format!("sync/{}", node)
+}
+
+impl Context {
+    /// Send what a shard asks for to its key. A CAS creates the key if this is our first total.
+    fn perform(&mut self, counter: &str, action: Option<cas::Action>) -> serde_json::Result<()> {
+        let Some(action) = action else {
+            return Ok(());
+        };
+        let key = shard_key(counter, &self.sender.node_id);
+        let request = match action {
+            cas::Action::Read => P::Read {
+                key: Some(key),
+                mode: None,
+            },
+            cas::Action::Cas { from, to } => P::Cas {
+                key,
+                from: from.into(),
+                to: to.into(),
+                create_if_not_exists: true,
+            },
+        };
+        let message = self.sender.message(&self.service, request)?;
+        self.writes.retain(|_, c| c != counter);
+        self.writes.insert(
+            message.body.msg_id.expect("No msg_id???"),
+            counter.to_string(),
+        );
+        self.sender.send_message(&message)
+    }
+    /// A shard's request was answered, so act on the answer and acknowledge adds that are now
+    /// in the store
+    fn shard_reply(
+        &mut self,
+        counter: String,
+        reply: impl FnOnce(&mut Shard) -> Option<cas::Action>,
+    ) -> serde_json::Result<()> {
+        let shard = self.shards.entry(counter.clone()).or_default();
+        let action = reply(shard);
+        let written = shard.written();
+        let (acked, unacked) = std::mem::take(&mut self.unacked)
+            .into_iter()
+            .partition(|(c, total, _)| *c == counter && *total <= written);
+        self.unacked = unacked;
+        for (_, _, add) in acked {
+            self.sender.respond(&add, P::AddOk {})?;
+        }
+        self.perform(&counter, action)
+    }
+    /// Start a client read. seq-kv is only sequentially consistent, so a plain read could be
+    /// arbitrarily stale. Writing a unique value first orders the read after everything seq-kv
+    /// had acknowledged when the write landed. lin-kv reads are never stale, so need no sync.
+    fn read(&mut self, request: Message<P>, counter: String) -> serde_json::Result<()> {
+        let pending = PendingRead {
+            request,
+            counter,
+            started: Instant::now(),
+            remaining: self.sender.node_ids.iter().cloned().collect(),
+            sum: 0,
+        };
+        if self.service == "lin-kv" {
+            let id = self.next_read;
+            self.next_read += 1;
+            return self.read_keys(id, pending);
+        }
+        let sync = self.sender.message(
+            &self.service,
+            P::Write {
+                key: sync_key(&self.sender.node_id),
+                value: pending.request.body.msg_id.unwrap_or_default(),
+            },
+        )?;
+        self.syncing
+            .insert(sync.body.msg_id.expect("No msg_id???"), pending);
+        self.sender.send_message(&sync)
+    }
+    fn write_ok(&mut self, in_reply_to: u64) -> serde_json::Result<()> {
+        match self.syncing.remove(&in_reply_to) {
+            Some(pending) => self.read_keys(in_reply_to, pending),
+            None => Ok(()),
+        }
+    }
+    /// Read every node's key for a read
+    fn read_keys(&mut self, id: u64, pending: PendingRead) -> serde_json::Result<()> {
+        for node in &pending.remaining {
+            let read = self.sender.message(
+                &self.service,
+                P::Read {
+                    key: Some(shard_key(&pending.counter, node)),
+                    mode: None,
+                },
+            )?;
+            self.key_reads
+                .insert(read.body.msg_id.expect("No msg_id???"), (id, node.clone()));
+            self.sender.send_message(&read)?;
+        }
+        self.reading.insert(id, pending);
+        Ok(())
+    }
+    /// A node's key was read, or found not to exist yet
+    fn key_read(&mut self, in_reply_to: u64, value: u64) -> serde_json::Result<()> {
+        let Some((read_id, node)) = self.key_reads.remove(&in_reply_to) else {
+            return Ok(());
+        };
+        let pending = self.reading.get_mut(&read_id).unwrap();
+        // Our own key can lag behind what we know
+        pending.sum += if node == self.sender.node_id {
+            let total = self
+                .shards
+                .get(&pending.counter)
+                .map_or(0, |shard| shard.total());
+            value.max(total)
+        } else {
+            value
+        };
+        pending.remaining.remove(&node);
+        if pending.remaining.is_empty() {
+            let pending = self.reading.remove(&read_id).unwrap();
+            let value = pending.sum;
+            self.sender
+                .respond(&pending.request, P::strict_read_ok(value))?;
+        }
+        Ok(())
+    }
+    /// Send again whatever the store has not answered within REQUEST_TIMEOUT. A read starts over
+    /// from its sync write, since the key reads it already has answers to may be stale by now.
+    fn retry(&mut self) -> serde_json::Result<()> {
+        let now = Instant::now();
+        let Some(cutoff) = now.checked_sub(REQUEST_TIMEOUT) else {
+            return Ok(());
+        };
+        let actions: Vec<(String, Option<cas::Action>)> = self
+            .shards
+            .iter_mut()
+            .map(|(counter, shard)| (counter.clone(), shard.tick(now)))
+            .collect();
+        for (counter, action) in actions {
+            self.perform(&counter, action)?;
+        }
+        let stale = |reads: &mut HashMap<u64, PendingRead>| -> Vec<(u64, PendingRead)> {
+            let ids: Vec<u64> = reads
+                .iter()
+                .filter(|(_, read)| read.started < cutoff)
+                .map(|(id, _)| *id)
+                .collect();
+            ids.into_iter()
+                .map(|id| (id, reads.remove(&id).unwrap()))
+                .collect()
+        };
+        let mut stale_reads = stale(&mut self.syncing);
+        stale_reads.extend(stale(&mut self.reading));
+        for (id, read) in stale_reads {
+            self.key_reads.retain(|_, (read_id, _)| *read_id != id);
+            self.read(read.request, read.counter)?;
+        }
+        Ok(())
+    }
+}
+
+/// What the sharded store's main loop reacts to
+enum Event {
+    Message(serde_json::Result<Message<P>>),
+    Tick,
 }
 
 fn main() -> serde_json::Result<()> {
-    let (server, mut sender) = server::init()?;
-    sender.send(
-        "seq-kv",
-        &P::Write {
-            key: "global".to_string(),
-            value: 0,
-        },
-    )?;
-    let context: Arc<Mutex<Context>> = Arc::new(Mutex::new(Context {
+    let (server, sender) = server::init()?;
+    let service = std::env::var(SERVICE_VAR).unwrap_or_else(|_| "seq-kv".to_string());
+    let strict = std::env::var(MODE_VAR).as_deref() == Ok("strict");
+    if std::env::var(STORE_VAR).as_deref() == Ok("global") {
+        return global::run(server, sender, service, strict);
+    }
+    let mut ctx = Context {
         sender,
-        delta: 0,
-        last_global: 0,
-        pendings: HashMap::new(),
-    }));
-    let thread_context = context.clone();
+        service,
+        strict,
+        shards: HashMap::new(),
+        unacked: vec![],
+        writes: HashMap::new(),
+        syncing: HashMap::new(),
+        reading: HashMap::new(),
+        next_read: 0,
+        key_reads: HashMap::new(),
+    };
+    // Messages and ticks both arrive here, so that the loop below has the context to itself
+    let (events_in, events) = mpsc::channel();
+    let ticks = events_in.clone();
     std::thread::spawn(move || loop {
-        std::thread::sleep(std::time::Duration::from_secs(1));
-        let mut ctx = thread_context.lock().unwrap();
-        let ctx = ctx.deref_mut();
-        // Reread the global value periodically for eventual consistency
-        ctx.sender
-            .send(
-                "seq-kv",
-                &P::Read {
-                    key: Some("global".to_string()),
-                },
-            )
-            .expect("Error sending reread");
+        std::thread::sleep(TICK_INTERVAL);
+        if ticks.send(Event::Tick).is_err() {
+            break;
+        }
     });
-    loop {
-        let message: Message<P> = server.read_message()?;
-        let mut ctx = context.lock().unwrap();
-        let mut ctx = ctx.deref_mut();
-        match message.body.fields {
-            // Increment our local delta appropriately
-            P::Add { delta } => {
-                ctx.delta += delta;
-                ctx.sender
-                    .send(
-                        "seq-kv",
-                        &P::Read {
-                            key: Some("global".to_string()),
-                        },
-                    )
-                    .expect("Error sending reread");
-                ctx.sender.respond(&message, &P::AddOk {})?
+    std::thread::spawn(move || loop {
+        let message = server.read_message();
+        let failed = message.is_err();
+        if events_in.send(Event::Message(message)).is_err() || failed {
+            break;
+        }
+    });
+    for event in events {
+        let message = match event {
+            Event::Message(message) => message?,
+            Event::Tick => {
+                ctx.retry()?;
+                continue;
             }
-            // Maelstrom wants to know what we think the global is, use the last_global
-            P::Read { key: None } => ctx.sender.respond(
-                &message,
-                &P::ReadOk {
-                    value: ctx.last_global,
-                },
-            )?,
-            // A read from the seq-kv has returned!
-            // Update our last_global, and send off a nice fresh CAS
-            P::ReadOk { value } => {
-                ctx.last_global = value;
-                if ctx.delta > 0 {
-                    let expected_value = ctx.last_global + ctx.delta;
-                    let request = &P::Cas {
-                        key: "global".to_string(),
-                        from: ctx.last_global,
-                        to: expected_value,
-                        create_if_not_exists: true,
-                    };
-                    let message = ctx.sender.message("seq-kv", request)?;
-                    ctx.pendings.insert(
-                        message.body.msg_id.expect("No msg_id???"),
-                        (ctx.delta, expected_value),
-                    );
-                    ctx.sender.send_message(&message)?;
+        };
+        let in_reply_to = message.body.in_reply_to.unwrap_or_default();
+        match message.body.fields {
+            P::Add { ref key, delta } => {
+                let counter = key.as_deref().unwrap_or(DEFAULT_COUNTER).to_string();
+                let shard = ctx.shards.entry(counter.clone()).or_default();
+                let action = shard.add(delta);
+                if ctx.strict && shard.written() < shard.total() {
+                    ctx.unacked
+                        .push((counter.clone(), shard.total(), message.clone()));
+                } else {
+                    ctx.sender.respond(&message, &P::AddOk {})?;
                 }
+                ctx.perform(&counter, action)?
             }
-            P::WriteOk {} => {}
-            // A CAS has succeeded!
-            // Look up which request succeeded and adjust delta and last_global accordingly.
-            P::CasOk {} => {
-                let (old_delta, old_expected_value) = *ctx
-                    .pendings
-                    .get(&message.body.in_reply_to.unwrap())
-                    .unwrap();
-                ctx.pendings.clear();
-                ctx.delta -= old_delta;
-                ctx.last_global = old_expected_value;
+            // Only clients send us reads
+            P::Read { ref key, .. } => {
+                let counter = key.as_deref().unwrap_or(DEFAULT_COUNTER).to_string();
+                ctx.read(message, counter)?
             }
-            P::Error { code, text } => {
-                if code == 20 {
-                    eprintln!("ERROR: {} {}", code, text);
-                } else if code == 22 {
-                    eprintln!("No cause for alarm, we are simply out of sync");
-                    ctx.sender
-                        .send(
-                            "seq-kv",
-                            &P::Read {
-                                key: Some("global".to_string()),
-                            },
-                        )
-                        .expect("Error sending reread");
-                } else {
-                    panic!("DISASTER {} {}", code, text);
+            _ if ctx.writes.contains_key(&in_reply_to) => {
+                let counter = ctx.writes.remove(&in_reply_to).unwrap();
+                match message.body.fields {
+                    P::CasOk {} => ctx.shard_reply(counter, Shard::cas_ok)?,
+                    P::ReadOk { value, .. } => {
+                        let value = value.as_u64().unwrap_or_default();
+                        ctx.shard_reply(counter, |shard| shard.read_ok(value))?
+                    }
+                    P::Error { code, .. } if code == KEY_DOES_NOT_EXIST => {
+                        ctx.shard_reply(counter, |shard| shard.read_ok(0))?
+                    }
+                    P::Error { code, .. } if code == PRECONDITION_FAILED => {
+                        ctx.shard_reply(counter, Shard::cas_failed)?
+                    }
+                    // Anything else, say the store being temporarily unavailable, is left for
+                    // retry to send again once it times out
+                    P::Error { .. } => {}
+                    _ => panic!("NOT ALLOWED"),
                 }
             }
-            _ => panic!("NOT ALLOWED"),
+            _ if message.body.in_reply_to.is_none() => panic!("NOT ALLOWED"),
+            P::WriteOk {} => ctx.write_ok(in_reply_to)?,
+            P::ReadOk { value, .. } => {
+                ctx.key_read(in_reply_to, value.as_u64().unwrap_or_default())?
+            }
+            P::Error { code, .. } if code == KEY_DOES_NOT_EXIST => ctx.key_read(in_reply_to, 0)?,
+            // Replies to requests that were given up on, say a CAS that finally failed, are
+            // dropped. Anything else, say the store being temporarily unavailable, leaves the
+            // read to start over once it times out.
+            _ => {}
         }
     }
+    Ok(())
 }
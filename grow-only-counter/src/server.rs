@@ -107,11 +107,6 @@ impl Sender {
         message.body.in_reply_to = to.body.msg_id;
         Ok(message)
     }
-    /// Send a message body to stdout
-    pub fn send<T: Serialize>(&mut self, to: &str, fields: T) -> Result<()> {
-        let message = self.message(to, fields)?;
-        self.send_message(&message)
-    }
     /// Respond to a message. If the message has a msg_id, set the in_reply_to appropriately
     pub fn respond<T: Serialize, U: Serialize>(
         &mut self,
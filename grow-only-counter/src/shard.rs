@@ -0,0 +1,181 @@
+//! This node's share of one sharded counter, as a state machine deciding what to send to the key
+//! holding it.
+//!
+//! Only this node ever changes its key, but it cannot blindly write its total to it. A write that
+//! is given up on after REQUEST_TIMEOUT may still be in transit, and landing after a newer one it
+//! would set the key back for good. Instead each total is written with a CAS from the last total
+//! the store confirmed. A late CAS finds the key already moved on and fails, rather than
+//! overwriting it. A CAS that fails means an earlier one landed after all, so the key is read to
+//! find out which, and the next CAS goes from there.
+use std::time::Instant;
+
+use crate::cas::Action;
+use crate::REQUEST_TIMEOUT;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Request {
+    /// A CAS up to this total
+    Cas(u64),
+    Read,
+}
+
+#[derive(Default)]
+pub struct Shard {
+    /// Our own total, which only we ever change
+    total: u64,
+    /// The total the store last confirmed, or 0 if it never has
+    written: u64,
+    /// The request waiting for an answer, and when it was sent
+    outstanding: Option<(Request, Instant)>,
+}
+
+impl Shard {
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+    pub fn add(&mut self, delta: u64) -> Option<Action> {
+        self.total += delta;
+        self.next()
+    }
+    pub fn cas_ok(&mut self) -> Option<Action> {
+        let Some((Request::Cas(to), _)) = self.outstanding else {
+            return None;
+        };
+        self.outstanding = None;
+        self.written = self.written.max(to);
+        self.next()
+    }
+    /// The key did not hold the last confirmed total, so a CAS given up on must have landed
+    pub fn cas_failed(&mut self) -> Option<Action> {
+        let Some((Request::Cas(_), _)) = self.outstanding else {
+            return None;
+        };
+        self.outstanding = Some((Request::Read, Instant::now()));
+        Some(Action::Read)
+    }
+    /// The store answered a read of the key. A key that does not exist yet reads as 0.
+    pub fn read_ok(&mut self, value: u64) -> Option<Action> {
+        let Some((Request::Read, _)) = self.outstanding else {
+            return None;
+        };
+        self.outstanding = None;
+        // Everything in the key came from us, so it is never past our total
+        self.written = self.written.max(value.min(self.total));
+        self.next()
+    }
+    /// Give up on a request that has gone unanswered for REQUEST_TIMEOUT, and send whatever
+    /// comes next. Replies to the request given up on must not be passed on after this.
+    pub fn tick(&mut self, now: Instant) -> Option<Action> {
+        match self.outstanding {
+            Some((_, since)) if now >= since + REQUEST_TIMEOUT => {
+                self.outstanding = None;
+                self.next()
+            }
+            _ => None,
+        }
+    }
+    /// Write the total if the store is behind it and nothing is outstanding
+    fn next(&mut self) -> Option<Action> {
+        if self.outstanding.is_some() || self.written >= self.total {
+            return None;
+        }
+        self.outstanding = Some((Request::Cas(self.total), Instant::now()));
+        Some(Action::Cas {
+            from: self.written,
+            to: self.total,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A key that applies CAS requests the way the store does
+    struct Key(u64);
+
+    impl Key {
+        fn cas(&mut self, action: Action) -> bool {
+            let Action::Cas { from, to } = action else {
+                panic!("Expected a CAS, got {:?}", action);
+            };
+            let landed = self.0 == from;
+            if landed {
+                self.0 = to;
+            }
+            landed
+        }
+    }
+
+    #[test]
+    fn totals_are_written_from_the_last_confirmed_one() {
+        let mut shard = Shard::default();
+        assert_eq!(shard.add(3), Some(Action::Cas { from: 0, to: 3 }));
+        assert_eq!(shard.add(4), None);
+        assert_eq!(shard.cas_ok(), Some(Action::Cas { from: 3, to: 7 }));
+        assert_eq!(shard.cas_ok(), None);
+        assert_eq!((shard.written(), shard.total()), (7, 7));
+        assert_eq!(shard.tick(Instant::now() + REQUEST_TIMEOUT), None);
+    }
+
+    #[test]
+    fn a_late_write_cannot_set_the_key_back() {
+        let mut key = Key(0);
+        let mut shard = Shard::default();
+        let first = shard.add(3).unwrap();
+        // The first CAS is held up in transit, and given up on
+        shard.add(4);
+        let second = shard.tick(Instant::now() + REQUEST_TIMEOUT).unwrap();
+        assert_eq!(second, Action::Cas { from: 0, to: 7 });
+        assert!(key.cas(second));
+        assert_eq!(shard.cas_ok(), None);
+        // When it does arrive, the first one finds the key moved on
+        assert!(!key.cas(first));
+        assert_eq!(key.0, 7);
+        assert_eq!(shard.written(), 7);
+    }
+
+    #[test]
+    fn a_write_given_up_on_that_landed_is_found_by_reading() {
+        let mut key = Key(0);
+        let mut shard = Shard::default();
+        let first = shard.add(3).unwrap();
+        assert!(key.cas(first));
+        // Its reply is lost, so the next CAS still goes from 0 and fails
+        shard.add(4);
+        let second = shard.tick(Instant::now() + REQUEST_TIMEOUT).unwrap();
+        assert!(!key.cas(second));
+        assert_eq!(shard.cas_failed(), Some(Action::Read));
+        let third = shard.read_ok(key.0).unwrap();
+        assert_eq!(third, Action::Cas { from: 3, to: 7 });
+        assert!(key.cas(third));
+        assert_eq!(shard.cas_ok(), None);
+        assert_eq!((key.0, shard.written()), (7, 7));
+    }
+
+    #[test]
+    fn unanswered_reads_are_given_up_on_too() {
+        let mut shard = Shard::default();
+        shard.add(3);
+        shard.cas_failed();
+        assert_eq!(shard.tick(Instant::now()), None);
+        assert_eq!(
+            shard.tick(Instant::now() + REQUEST_TIMEOUT),
+            Some(Action::Cas { from: 0, to: 3 })
+        );
+    }
+
+    #[test]
+    fn unexpected_replies_are_ignored() {
+        let mut shard = Shard::default();
+        assert_eq!(shard.cas_ok(), None);
+        assert_eq!(shard.cas_failed(), None);
+        assert_eq!(shard.read_ok(5), None);
+        shard.add(3);
+        assert_eq!(shard.read_ok(5), None);
+        assert_eq!(shard.written(), 0);
+    }
+}
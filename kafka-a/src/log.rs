@@ -0,0 +1,144 @@
+//! Storage for each key's log, behind a trait so that other backends can be swapped in.
+use crate::{Entry, Offset};
+
+pub trait LogStore {
+    /// Add an entry at the end. `offset` must be at least the high watermark, and below
+    /// Offset::MAX so that the high watermark after it can be represented.
+    fn append(&mut self, offset: Offset, entry: Entry);
+    /// Up to `limit` entries, starting from the first at or after `offset`. Empty if there are
+    /// none, including when `offset` is past the end.
+    fn read_from(&self, offset: Offset, limit: usize) -> Vec<(Offset, Entry)>;
+    /// The offset after the last entry ever appended, or 0 if there never was one. Truncating
+    /// does not lower it, so offsets are never reused.
+    fn high_watermark(&self) -> Offset;
+    /// Drop every entry before `offset`
+    fn truncate_before(&mut self, offset: Offset);
+}
+
+/// The offset of the last entry ever appended to a log, if any
+pub fn last_offset(log: &dyn LogStore) -> Option<Offset> {
+    let high_watermark = log.high_watermark();
+    (high_watermark > 0).then(|| high_watermark - 1)
+}
+
+/// Entries sorted by offset, in memory
+#[derive(Default)]
+pub struct MemoryLog {
+    entries: Vec<(Offset, Entry)>,
+    high_watermark: Offset,
+}
+
+impl MemoryLog {
+    /// The index of the first entry at or after `offset`
+    fn index(&self, offset: Offset) -> usize {
+        self.entries
+            .partition_point(|(existing, _)| *existing < offset)
+    }
+}
+
+impl LogStore for MemoryLog {
+    fn append(&mut self, offset: Offset, entry: Entry) {
+        assert!(
+            offset >= self.high_watermark,
+            "Offset {} is below the high watermark {}",
+            offset,
+            self.high_watermark
+        );
+        let high_watermark = offset
+            .checked_add(1)
+            .expect("Offset::MAX is past the last offset a log can hold");
+        self.entries.push((offset, entry));
+        self.high_watermark = high_watermark;
+    }
+    fn read_from(&self, offset: Offset, limit: usize) -> Vec<(Offset, Entry)> {
+        let start = self.index(offset);
+        let end = self.entries.len().min(start.saturating_add(limit));
+        self.entries[start..end].to_vec()
+    }
+    fn high_watermark(&self) -> Offset {
+        self.high_watermark
+    }
+    fn truncate_before(&mut self, offset: Offset) {
+        let index = self.index(offset);
+        self.entries.drain(..index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sparse_log() -> MemoryLog {
+        let mut log = MemoryLog::default();
+        for offset in [0, 10, 20, 30] {
+            log.append(offset, offset as Entry / 10);
+        }
+        log
+    }
+
+    #[test]
+    fn empty_log() {
+        let log = MemoryLog::default();
+        assert_eq!(log.read_from(0, 10), vec![]);
+        assert_eq!(log.read_from(-5, 10), vec![]);
+        assert_eq!(log.high_watermark(), 0);
+        assert_eq!(last_offset(&log), None);
+    }
+
+    #[test]
+    fn reads_start_at_the_first_entry_at_or_after_the_offset() {
+        let log = sparse_log();
+        assert_eq!(log.read_from(10, 2), vec![(10, 1), (20, 2)]);
+        assert_eq!(log.read_from(11, 10), vec![(20, 2), (30, 3)]);
+        assert_eq!(log.read_from(-1, 1), vec![(0, 0)]);
+        assert_eq!(log.read_from(0, 0), vec![]);
+        assert_eq!(log.read_from(0, usize::MAX).len(), 4);
+    }
+
+    #[test]
+    fn reads_past_the_end_are_empty() {
+        let log = sparse_log();
+        assert_eq!(log.read_from(31, 10), vec![]);
+        assert_eq!(log.read_from(Offset::MAX, 10), vec![]);
+        assert_eq!(log.high_watermark(), 31);
+        assert_eq!(last_offset(&log), Some(30));
+    }
+
+    #[test]
+    fn truncation_keeps_the_high_watermark() {
+        let mut log = sparse_log();
+        log.truncate_before(15);
+        assert_eq!(log.read_from(0, 10), vec![(20, 2), (30, 3)]);
+        log.truncate_before(100);
+        assert_eq!(log.read_from(0, 10), vec![]);
+        assert_eq!(log.high_watermark(), 31);
+        log.append(31, 4);
+        assert_eq!(log.read_from(0, 10), vec![(31, 4)]);
+    }
+
+    #[test]
+    fn appending_just_below_the_maximum_offset() {
+        let mut log = MemoryLog::default();
+        log.append(Offset::MAX - 1, 7);
+        assert_eq!(log.high_watermark(), Offset::MAX);
+        assert_eq!(last_offset(&log), Some(Offset::MAX - 1));
+        assert_eq!(
+            log.read_from(Offset::MAX - 1, 10),
+            vec![(Offset::MAX - 1, 7)]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn appending_at_the_maximum_offset_panics() {
+        let mut log = MemoryLog::default();
+        log.append(Offset::MAX, 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn appending_below_the_high_watermark_panics() {
+        let mut log = sparse_log();
+        log.append(30, 0);
+    }
+}
@@ -1,13 +1,26 @@
 use std::collections::HashMap;
 
+use log::{LogStore, MemoryLog};
 use serde::{Deserialize, Serialize};
 use server::Message;
 
+mod clock;
+mod log;
+mod offsets;
+mod poll;
 mod server;
 
 type Entry = usize;
 type Offset = i64;
 
+/// Environment variable selecting the clock offsets are drawn from, see clock::from_config
+const CLOCK_VAR: &str = "KAFKA_CLOCK";
+/// Environment variable selecting how offsets are allocated, see offsets::from_config
+const OFFSETS_VAR: &str = "KAFKA_OFFSETS";
+/// Environment variable that, set to "committed", drops entries once their offset is committed.
+/// Only safe while a single consumer group reads each key.
+const RETENTION_VAR: &str = "KAFKA_RETENTION";
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
@@ -21,9 +34,16 @@ enum P {
     },
     Poll {
         offsets: HashMap<String, Offset>,
+        /// Lower the limits across all keys for this poll, see poll::Limits
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_messages: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_bytes: Option<usize>,
     },
     PollOk {
         msgs: HashMap<String, Vec<(Offset, Entry)>>,
+        /// Whether the limits left entries out
+        more: bool,
     },
     CommitOffsets {
         offsets: HashMap<String, Offset>,
@@ -41,58 +61,53 @@ enum P {
     },
 }
 
-fn binary_search<T>(arr: &[(Offset, T)], offset: Offset) -> usize {
-    if arr[0].0 >= offset {
-        return 0;
-    }
-    let mut left = 0;
-    let mut right = arr.len();
-    while left + 1 < right {
-        let index = left + ((right - left) / 2);
-        if arr[index].0 < offset {
-            left = index;
-        } else {
-            right = index;
-        }
-    }
-    right
-}
-
 fn main() -> serde_json::Result<()> {
     let (server, mut sender) = server::init()?;
-    let mut logs = HashMap::<String, Vec<(Offset, Entry)>>::new();
+    sender.clock = clock::from_config(
+        &std::env::var(CLOCK_VAR).unwrap_or_default(),
+        &sender.node_id,
+    );
+    let allocator = offsets::from_config(&std::env::var(OFFSETS_VAR).unwrap_or_default());
+    let limits = poll::Limits::from_env();
+    let drop_committed = std::env::var(RETENTION_VAR).as_deref() == Ok("committed");
+    let mut logs = HashMap::<String, MemoryLog>::new();
     let mut commits = HashMap::<String, Offset>::new();
     loop {
-        let message: Message<P> = server.read_message()?;
+        let message: Message<P> = server.receive(&mut sender)?;
         match &message.body.fields {
             P::Send { key, msg } => {
-                if !logs.contains_key(key) {
-                    logs.insert(key.clone(), vec![]);
-                }
-                let log = &mut logs.get_mut(key).unwrap();
-                let offset = chrono::offset::Utc::now().timestamp_micros();
-                log.push((offset, *msg));
+                let log = logs.entry(key.clone()).or_default();
+                let offset = allocator.allocate(log::last_offset(log), sender.clock.as_mut());
+                log.append(offset, *msg);
                 sender.respond(&message, &P::SendOk { offset })?;
             }
-            P::Poll { offsets } => {
-                let msgs = offsets
+            P::Poll {
+                offsets,
+                max_messages,
+                max_bytes,
+            } => {
+                let limits = limits.request(*max_messages, *max_bytes);
+                // One more than the limit, so that take can tell whether entries were left out
+                let limit = limits.max_messages.saturating_add(1);
+                let available = offsets
                     .iter()
-                    .filter(|(key, _)| logs.contains_key(*key))
-                    .map(|(key, &offset)| {
-                        let log = logs.get(key).unwrap();
-                        let index = binary_search(log, offset);
-                        (
-                            key.clone(),
-                            // Just in case, limit response to 10 entries
-                            Vec::from(&log[index..log.len().min(index + 10)]),
-                        )
+                    .filter_map(|(key, &offset)| {
+                        let log = logs.get(key)?;
+                        Some((key.clone(), log.read_from(offset, limit)))
                     })
                     .collect();
-                sender.respond(&message, &P::PollOk { msgs })?;
+                let (msgs, more) = poll::take(available, limits);
+                sender.respond(&message, &P::PollOk { msgs, more })?;
             }
             P::CommitOffsets { offsets } => {
                 offsets.iter().for_each(|(key, &offset)| {
-                    commits.insert(key.clone(), offset);
+                    // A late commit must not lower a newer one, or entries it still needs could
+                    // already have been dropped
+                    let committed = commits.entry(key.clone()).or_insert(offset);
+                    *committed = (*committed).max(offset);
+                    if let Some(log) = logs.get_mut(key).filter(|_| drop_committed) {
+                        log.truncate_before(*committed);
+                    }
                 });
                 sender.respond(&message, &P::CommitOffsetsOk {})?;
             }
@@ -4,6 +4,8 @@ use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Result;
 use std::hash::{Hash, Hasher};
 
+use crate::clock::{Clock, HybridClock, Timestamp};
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message<T> {
     pub src: String,
@@ -15,6 +17,9 @@ pub struct Message<T> {
 pub struct Body<T> {
     pub msg_id: Option<u64>,
     pub in_reply_to: Option<u64>,
+    /// The sender's logical time, absent on messages from clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock: Option<Timestamp>,
     #[serde(flatten)]
     pub fields: T,
 }
@@ -43,11 +48,21 @@ impl Server {
         let mut deserializer = serde_json::Deserializer::from_reader(stdin);
         Message::deserialize(&mut deserializer)
     }
+    /// Read a message and catch the sender's clock up with it
+    pub fn receive<T: DeserializeOwned>(&self, sender: &mut Sender) -> Result<Message<T>> {
+        let message = self.read_message()?;
+        if let Some(time) = &message.body.clock {
+            sender.clock.observe(time);
+        }
+        Ok(message)
+    }
 }
 
 pub struct Sender {
     pub node_id: String,
     pub node_ids: Vec<String>,
+    /// Stamped on every message to another node. Hybrid unless the workload picks another.
+    pub clock: Box<dyn Clock>,
     counter: u64,
 }
 
@@ -62,6 +77,7 @@ impl Sender {
                 Sender {
                     node_id: node_id.clone(),
                     node_ids: node_ids.clone(),
+                    clock: Box::<HybridClock>::default(),
                     counter,
                 }
             }
@@ -82,13 +98,19 @@ impl Sender {
             .expect("Error writing newline");
         Ok(())
     }
-    /// Adds the msg_id field to a body and wraps it in a Message
+    /// Adds the msg_id field to a body, and the clock field if it is going to another node, and
+    /// wraps it in a Message. Clients do not expect a clock field, and have no use for one.
     pub fn message<T: Serialize>(&mut self, to: &str, fields: T) -> Result<Message<T>> {
         let msg_id = self.counter;
         self.counter += 1;
         let body = Body {
             msg_id: Some(msg_id),
             in_reply_to: None,
+            clock: self
+                .node_ids
+                .iter()
+                .any(|node| node == to)
+                .then(|| self.clock.tick()),
             fields,
         };
         Ok(Message {
@@ -107,11 +129,6 @@ impl Sender {
         message.body.in_reply_to = to.body.msg_id;
         Ok(message)
     }
-    /// Send a message body to stdout
-    pub fn send<T: Serialize>(&mut self, to: &str, fields: T) -> Result<()> {
-        let message = self.message(to, fields)?;
-        self.send_message(&message)
-    }
     /// Respond to a message. If the message has a msg_id, set the in_reply_to appropriately
     pub fn respond<T: Serialize, U: Serialize>(
         &mut self,
@@ -0,0 +1,291 @@
+//! Logical clocks for ordering events across nodes without trusting wall clocks.
+//!
+//! Every message the Sender creates for another node carries the sender's current time, and
+//! Server::receive catches the local clock up with the time on every message it reads. Each clock
+//! guarantees that if event a happened before event b, then a's timestamp is less than b's.
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// A point in time on any of the clocks. Untagged, since each kind serializes to a different JSON
+/// type: Lamport to a number, hybrid to a pair and vector to an object.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Timestamp {
+    Lamport(u64),
+    Hybrid(HybridTimestamp),
+    Vector(VectorTimestamp),
+}
+
+impl Timestamp {
+    /// A single number that respects causality, so if a happened before b then
+    /// a.scalar() < b.scalar(). The converse does not hold: concurrent events get distinct but
+    /// arbitrarily ordered numbers. A hybrid time takes 16 bits for its logical counter, which
+    /// HybridClock carries into the wall time before it overflows.
+    pub fn scalar(&self) -> i64 {
+        match self {
+            Timestamp::Lamport(time) => *time as i64,
+            Timestamp::Hybrid(HybridTimestamp(wall, logical)) => {
+                ((*wall as i64) << 16) + *logical as i64
+            }
+            Timestamp::Vector(VectorTimestamp(entries)) => entries.values().sum::<u64>() as i64,
+        }
+    }
+}
+
+pub trait Clock: Send {
+    /// Advance for a local event or a send, returning the new time
+    fn tick(&mut self) -> Timestamp;
+    /// Catch up with a time received from another node. Times from a different kind of clock
+    /// are ignored.
+    fn observe(&mut self, time: &Timestamp);
+}
+
+/// Pick a clock by name: "lamport", "vector" or "hybrid", the default
+pub fn from_config(name: &str, node_id: &str) -> Box<dyn Clock> {
+    match name {
+        "lamport" => Box::<LamportClock>::default(),
+        "vector" => Box::new(VectorClock::new(node_id)),
+        _ => Box::<HybridClock>::default(),
+    }
+}
+
+/// A counter that jumps past every counter it hears of
+#[derive(Default)]
+pub struct LamportClock {
+    time: u64,
+}
+
+impl Clock for LamportClock {
+    fn tick(&mut self) -> Timestamp {
+        self.time += 1;
+        Timestamp::Lamport(self.time)
+    }
+    fn observe(&mut self, time: &Timestamp) {
+        if let Timestamp::Lamport(time) = time {
+            self.time = self.time.max(*time) + 1;
+        }
+    }
+}
+
+/// One counter per node. Unlike the other clocks, two vector times can be compared to tell
+/// whether one happened before the other or they were concurrent.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct VectorTimestamp(pub BTreeMap<String, u64>);
+
+impl VectorTimestamp {
+    /// The pointwise maximum, which is the earliest time after both
+    pub fn merge(&mut self, other: &VectorTimestamp) {
+        for (node, &time) in &other.0 {
+            let entry = self.0.entry(node.clone()).or_default();
+            *entry = (*entry).max(time);
+        }
+    }
+    fn get(&self, node: &str) -> u64 {
+        self.0.get(node).copied().unwrap_or_default()
+    }
+}
+
+/// Less if every entry is at most the other's, greater if every entry is at least the other's,
+/// and None if the times are concurrent
+impl PartialOrd for VectorTimestamp {
+    fn partial_cmp(&self, other: &VectorTimestamp) -> Option<Ordering> {
+        let mut ordering = Ordering::Equal;
+        for node in self.0.keys().chain(other.0.keys()) {
+            match (ordering, self.get(node).cmp(&other.get(node))) {
+                (_, Ordering::Equal) => {}
+                (Ordering::Equal, entry) => ordering = entry,
+                (current, entry) if current != entry => return None,
+                _ => {}
+            }
+        }
+        Some(ordering)
+    }
+}
+
+pub struct VectorClock {
+    node_id: String,
+    time: VectorTimestamp,
+}
+
+impl VectorClock {
+    pub fn new(node_id: &str) -> VectorClock {
+        VectorClock {
+            node_id: node_id.to_string(),
+            time: VectorTimestamp::default(),
+        }
+    }
+}
+
+impl Clock for VectorClock {
+    fn tick(&mut self) -> Timestamp {
+        *self.time.0.entry(self.node_id.clone()).or_default() += 1;
+        Timestamp::Vector(self.time.clone())
+    }
+    fn observe(&mut self, time: &Timestamp) {
+        if let Timestamp::Vector(time) = time {
+            self.time.merge(time);
+            *self.time.0.entry(self.node_id.clone()).or_default() += 1;
+        }
+    }
+}
+
+/// Milliseconds of wall clock time, and a logical counter for events within the same
+/// millisecond or while the wall clock lags behind another node's
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HybridTimestamp(pub u64, pub u32);
+
+/// The largest logical counter that fits in Timestamp::scalar's low bits
+const MAX_LOGICAL: u32 = 0xffff;
+
+impl HybridTimestamp {
+    /// The earliest time after this one. Moves on to the next millisecond rather than letting
+    /// the logical counter grow past MAX_LOGICAL.
+    fn successor(self) -> HybridTimestamp {
+        let HybridTimestamp(wall, logical) = self;
+        if logical >= MAX_LOGICAL {
+            HybridTimestamp(wall + 1, 0)
+        } else {
+            HybridTimestamp(wall, logical + 1)
+        }
+    }
+}
+
+/// A hybrid logical clock (Kulkarni et al., 2014). Stays within clock skew of the wall clock,
+/// which makes its times meaningful to people, but never goes backwards when the wall clock is
+/// adjusted or a node's clock is behind.
+#[derive(Default)]
+pub struct HybridClock {
+    last: HybridTimestamp,
+}
+
+impl Clock for HybridClock {
+    fn tick(&mut self) -> Timestamp {
+        let now = wall_ms();
+        self.last = if now > self.last.0 {
+            HybridTimestamp(now, 0)
+        } else {
+            self.last.successor()
+        };
+        Timestamp::Hybrid(self.last)
+    }
+    fn observe(&mut self, time: &Timestamp) {
+        let Timestamp::Hybrid(HybridTimestamp(theirs, their_logical)) = *time else {
+            return;
+        };
+        let HybridTimestamp(ours, our_logical) = self.last;
+        let wall = ours.max(theirs).max(wall_ms());
+        self.last = match (wall == ours, wall == theirs) {
+            (true, true) => HybridTimestamp(wall, our_logical.max(their_logical)).successor(),
+            (true, false) => HybridTimestamp(wall, our_logical).successor(),
+            (false, true) => HybridTimestamp(wall, their_logical).successor(),
+            (false, false) => HybridTimestamp(wall, 0),
+        };
+    }
+}
+
+fn wall_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Clock is before the epoch")
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vector(entries: &[(&str, u64)]) -> VectorTimestamp {
+        VectorTimestamp(
+            entries
+                .iter()
+                .map(|&(node, time)| (node.to_string(), time))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn vector_times_compare_entrywise() {
+        let a = vector(&[("n0", 1), ("n1", 2)]);
+        assert_eq!(a.partial_cmp(&a.clone()), Some(Ordering::Equal));
+        // A missing entry is a zero
+        assert_eq!(
+            a.partial_cmp(&vector(&[("n0", 1), ("n1", 2), ("n2", 0)])),
+            Some(Ordering::Equal)
+        );
+        let later = vector(&[("n0", 1), ("n1", 3)]);
+        assert_eq!(a.partial_cmp(&later), Some(Ordering::Less));
+        assert_eq!(later.partial_cmp(&a), Some(Ordering::Greater));
+        assert_eq!(
+            a.partial_cmp(&vector(&[("n0", 1), ("n1", 2), ("n2", 1)])),
+            Some(Ordering::Less)
+        );
+    }
+
+    #[test]
+    fn concurrent_vector_times_are_unordered() {
+        let a = vector(&[("n0", 2), ("n1", 1)]);
+        let b = vector(&[("n0", 1), ("n1", 2)]);
+        assert_eq!(a.partial_cmp(&b), None);
+        assert_eq!(b.partial_cmp(&a), None);
+        assert_eq!(
+            vector(&[("n0", 1)]).partial_cmp(&vector(&[("n1", 1)])),
+            None
+        );
+    }
+
+    #[test]
+    fn vector_clocks_order_send_before_receive() {
+        let (mut n0, mut n1) = (VectorClock::new("n0"), VectorClock::new("n1"));
+        n1.tick();
+        let Timestamp::Vector(sent) = n0.tick() else {
+            unreachable!()
+        };
+        n1.observe(&Timestamp::Vector(sent.clone()));
+        let Timestamp::Vector(after) = n1.tick() else {
+            unreachable!()
+        };
+        assert_eq!(sent.partial_cmp(&after), Some(Ordering::Less));
+    }
+
+    #[test]
+    fn hybrid_receive_is_after_a_send_from_the_future() {
+        let mut clock = HybridClock::default();
+        let before = clock.tick();
+        let sent = HybridTimestamp(wall_ms() + 60_000, 7);
+        clock.observe(&Timestamp::Hybrid(sent));
+        assert_eq!(clock.last, HybridTimestamp(sent.0, 8));
+        let after = clock.tick();
+        assert!(before.scalar() < Timestamp::Hybrid(sent).scalar());
+        assert!(Timestamp::Hybrid(sent).scalar() < after.scalar());
+    }
+
+    #[test]
+    fn hybrid_receive_from_the_past_keeps_local_time() {
+        let mut clock = HybridClock::default();
+        clock.tick();
+        let ours = clock.last;
+        clock.observe(&Timestamp::Hybrid(HybridTimestamp(1, 5)));
+        assert!(clock.last > ours);
+    }
+
+    #[test]
+    fn hybrid_logical_overflow_carries_into_wall_time() {
+        let last = HybridTimestamp(wall_ms() + 60_000, MAX_LOGICAL);
+        let mut clock = HybridClock { last };
+        let next = clock.tick();
+        assert_eq!(next, Timestamp::Hybrid(HybridTimestamp(last.0 + 1, 0)));
+        assert!(Timestamp::Hybrid(last).scalar() < next.scalar());
+    }
+
+    #[test]
+    fn scalar_carries_foreign_logical_counters() {
+        let big = Timestamp::Hybrid(HybridTimestamp(10, MAX_LOGICAL + 1));
+        assert_eq!(
+            big.scalar(),
+            Timestamp::Hybrid(HybridTimestamp(11, 0)).scalar()
+        );
+    }
+}
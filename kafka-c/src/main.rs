@@ -0,0 +1,577 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use log::{LogStore, MemoryLog};
+use offsets::OffsetAllocator;
+use serde::{Deserialize, Serialize};
+use server::{Message, Sender};
+
+mod clock;
+mod log;
+mod offsets;
+mod poll;
+mod server;
+
+type Entry = usize;
+type Offset = i64;
+
+/// Environment variable selecting the clock offsets are drawn from, see clock::from_config
+const CLOCK_VAR: &str = "KAFKA_CLOCK";
+/// Environment variable selecting how offsets are allocated, see offsets::from_config
+const OFFSETS_VAR: &str = "KAFKA_OFFSETS";
+/// Environment variable that, set to "committed", drops entries once their offset is committed.
+/// Only safe while a single consumer group reads each key, and only applies to commits the key's
+/// owner takes.
+const RETENTION_VAR: &str = "KAFKA_RETENTION";
+
+/// lin-kv's error code for reading a key that has never been written
+const KEY_DOES_NOT_EXIST: u64 = 20;
+/// How often to look for requests that have gone unanswered
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait for a peer or lin-kv before assuming a request or its reply was lost, and
+/// sending it again
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum P {
+    /// From a client, or forwarded to the key's owner
+    Send {
+        key: String,
+        msg: Entry,
+        /// Set when forwarded, so that the owner appends a send forwarded again only once
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    SendOk {
+        offset: Offset,
+    },
+    /// From a client, or from a peer for keys we own
+    Poll {
+        offsets: HashMap<String, Offset>,
+        /// Lower the limits across all keys for this poll, see poll::Limits
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_messages: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_bytes: Option<usize>,
+    },
+    PollOk {
+        msgs: HashMap<String, Vec<(Offset, Entry)>>,
+        /// Whether the limits left entries out
+        more: bool,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, Offset>,
+    },
+    CommitOffsetsOk,
+    ListCommittedOffsets {
+        keys: Vec<String>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, Offset>,
+    },
+    Read {
+        key: String,
+    },
+    ReadOk {
+        value: Offset,
+    },
+    Cas {
+        key: String,
+        from: Offset,
+        to: Offset,
+        create_if_not_exists: bool,
+    },
+    CasOk {},
+    Error {
+        code: u64,
+        text: String,
+    },
+}
+
+/// A client request waiting on peers or lin-kv
+struct Pending {
+    request: Message<P>,
+    /// Replies still to come
+    remaining: usize,
+    msgs: HashMap<String, Vec<(Offset, Entry)>>,
+    /// Whether an owner left entries out of its part of a poll
+    more: bool,
+    offsets: HashMap<String, Offset>,
+}
+
+/// A request sent on behalf of a pending client request, kept to send again if it goes
+/// unanswered
+struct Outstanding {
+    /// The pending request it is for
+    id: u64,
+    /// The key it is for, if it matters
+    key: Option<String>,
+    to: String,
+    fields: P,
+    sent: Instant,
+}
+
+/// Every key is owned by one node, which alone appends to its log and so can hand out offsets
+/// without coordinating. Other nodes forward sends to the owner and ask it when polled. Committed
+/// offsets are kept in lin-kv, which every node can reach, and only ever raised: a commit reads
+/// the current offset and CASes it up, so a commit that arrives late cannot undo a newer one.
+/// Requests to peers and lin-kv that go unanswered are sent again, so a lost message delays a
+/// client request but never strands it.
+struct Context {
+    sender: Sender,
+    /// Logs of the keys we own
+    logs: HashMap<String, MemoryLog>,
+    /// Offsets of the sends forwarded to us, by their id
+    forwarded: HashMap<String, Offset>,
+    /// Whether to drop entries from our logs once they are committed here
+    drop_committed: bool,
+    allocator: Box<dyn OffsetAllocator>,
+    limits: poll::Limits,
+    pending: HashMap<u64, Pending>,
+    /// Requests waiting for a reply, by msg_id
+    outstanding: HashMap<u64, Outstanding>,
+    next_pending: u64,
+}
+
+/// The node owning a key, by hashing it over the cluster
+fn owner<'a>(key: &str, node_ids: &'a [String]) -> &'a str {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    &node_ids[(hasher.finish() % node_ids.len() as u64) as usize]
+}
+
+/// Which keys each node owns, for the keys in a poll
+fn by_owner(
+    offsets: &HashMap<String, Offset>,
+    node_ids: &[String],
+) -> HashMap<String, HashMap<String, Offset>> {
+    let mut by_owner = HashMap::<String, HashMap<String, Offset>>::new();
+    for (key, &offset) in offsets {
+        by_owner
+            .entry(owner(key, node_ids).to_string())
+            .or_default()
+            .insert(key.clone(), offset);
+    }
+    by_owner
+}
+
+/// The lin-kv key holding a key's committed offset
+fn commit_key(key: &str) -> String {
+    format!("commit/{}", key)
+}
+
+/// What to ask lin-kv next to commit `offset` for `key`, given its reply to the last request, or
+/// None once the committed offset is at least `offset`
+fn commit_step(key: &str, offset: Offset, reply: &P) -> Option<P> {
+    match reply {
+        P::ReadOk { value } if *value >= offset => None,
+        P::ReadOk { value } => Some(P::Cas {
+            key: commit_key(key),
+            from: *value,
+            to: offset,
+            create_if_not_exists: false,
+        }),
+        P::Error { code, .. } if *code == KEY_DOES_NOT_EXIST => Some(P::Cas {
+            key: commit_key(key),
+            from: offset,
+            to: offset,
+            create_if_not_exists: true,
+        }),
+        P::CasOk {} => None,
+        // Another commit got there first, so see whether it was higher. Anything else, say a
+        // timeout or lin-kv being temporarily unavailable, may or may not have landed, so start
+        // over from a read too.
+        P::Error { .. } => Some(P::Read {
+            key: commit_key(key),
+        }),
+        _ => panic!("NOT ALLOWED: {:?}", reply),
+    }
+}
+
+impl Context {
+    fn owns(&self, key: &str) -> bool {
+        owner(key, &self.sender.node_ids) == self.sender.node_id
+    }
+    fn append(&mut self, key: &str, msg: Entry) -> Offset {
+        let log = self.logs.entry(key.to_string()).or_default();
+        let offset = self
+            .allocator
+            .allocate(log::last_offset(log), self.sender.clock.as_mut());
+        log.append(offset, msg);
+        offset
+    }
+    /// Poll the keys we own, and say whether the limits left entries out
+    fn poll_local(
+        &self,
+        offsets: &HashMap<String, Offset>,
+        limits: poll::Limits,
+    ) -> (HashMap<String, Vec<(Offset, Entry)>>, bool) {
+        // One more than the limit, so that take can tell whether entries were left out
+        let limit = limits.max_messages.saturating_add(1);
+        let available = offsets
+            .iter()
+            .filter_map(|(key, &offset)| {
+                let log = self.logs.get(key)?;
+                Some((key.clone(), log.read_from(offset, limit)))
+            })
+            .collect();
+        poll::take(available, limits)
+    }
+    /// Track a client request. Call finish once everything it needs has been requested.
+    fn start(&mut self, pending: Pending) -> u64 {
+        let id = self.next_pending;
+        self.next_pending += 1;
+        self.pending.insert(id, pending);
+        id
+    }
+    /// Send a request on behalf of a pending client request
+    fn request(
+        &mut self,
+        id: u64,
+        to: &str,
+        fields: P,
+        key: Option<String>,
+    ) -> serde_json::Result<()> {
+        self.pending.get_mut(&id).unwrap().remaining += 1;
+        self.send(Outstanding {
+            id,
+            key,
+            to: to.to_string(),
+            fields,
+            sent: Instant::now(),
+        })
+    }
+    fn send(&mut self, mut outstanding: Outstanding) -> serde_json::Result<()> {
+        let message = self
+            .sender
+            .message(&outstanding.to, outstanding.fields.clone())?;
+        outstanding.sent = Instant::now();
+        self.outstanding
+            .insert(message.body.msg_id.expect("No msg_id???"), outstanding);
+        self.sender.send_message(&message)
+    }
+    /// Send again whatever has not been answered within REQUEST_TIMEOUT. Replies to the requests
+    /// given up on are dropped, so each is only counted once.
+    fn retry(&mut self) -> serde_json::Result<()> {
+        let Some(cutoff) = Instant::now().checked_sub(REQUEST_TIMEOUT) else {
+            return Ok(());
+        };
+        let lost: Vec<u64> = self
+            .outstanding
+            .iter()
+            .filter(|(_, outstanding)| outstanding.sent < cutoff)
+            .map(|(msg_id, _)| *msg_id)
+            .collect();
+        for msg_id in lost {
+            let outstanding = self.outstanding.remove(&msg_id).unwrap();
+            self.send(outstanding)?;
+        }
+        Ok(())
+    }
+    /// Answer a pending client request once every reply is in
+    fn finish(&mut self, id: u64) -> serde_json::Result<()> {
+        if self.pending[&id].remaining > 0 {
+            return Ok(());
+        }
+        let pending = self.pending.remove(&id).unwrap();
+        let response = match &pending.request.body.fields {
+            P::Send { key, .. } => P::SendOk {
+                offset: pending.offsets[key],
+            },
+            // Every owner kept to the limits, but between them they can still go over
+            P::Poll {
+                max_messages,
+                max_bytes,
+                ..
+            } => {
+                let limits = self.limits.request(*max_messages, *max_bytes);
+                let (msgs, more) = poll::take(pending.msgs.into_iter().collect(), limits);
+                P::PollOk {
+                    msgs,
+                    more: more || pending.more,
+                }
+            }
+            P::CommitOffsets { .. } => P::CommitOffsetsOk,
+            P::ListCommittedOffsets { .. } => P::ListCommittedOffsetsOk {
+                offsets: pending.offsets,
+            },
+            _ => panic!("NOT ALLOWED: {:?}", pending.request),
+        };
+        self.sender.respond(&pending.request, &response)
+    }
+    fn pending(request: &Message<P>) -> Pending {
+        Pending {
+            request: request.clone(),
+            remaining: 0,
+            msgs: HashMap::new(),
+            more: false,
+            offsets: HashMap::new(),
+        }
+    }
+    /// Handle a reply to something we sent on behalf of a client request
+    fn reply(&mut self, message: &Message<P>) -> serde_json::Result<()> {
+        let in_reply_to = message.body.in_reply_to.unwrap_or_default();
+        let Some(outstanding) = self.outstanding.remove(&in_reply_to) else {
+            return Ok(());
+        };
+        let Outstanding { id, key, .. } = &outstanding;
+        let (id, key) = (*id, key.clone());
+        let pending = self.pending.get_mut(&id).unwrap();
+        if let P::CommitOffsets { offsets } = &pending.request.body.fields {
+            pending.remaining -= 1;
+            let key = key.unwrap();
+            let offset = offsets[&key];
+            match commit_step(&key, offset, &message.body.fields) {
+                Some(next) => self.request(id, "lin-kv", next, Some(key))?,
+                // The committed offset is at least ours now, so nothing before it is needed
+                None => {
+                    if let Some(log) = self.logs.get_mut(&key).filter(|_| self.drop_committed) {
+                        log.truncate_before(offset);
+                    }
+                }
+            }
+            return self.finish(id);
+        }
+        match &message.body.fields {
+            P::SendOk { offset } | P::ReadOk { value: offset } => {
+                pending.offsets.insert(key.unwrap(), *offset);
+            }
+            P::PollOk { msgs, more } => {
+                pending.msgs.extend(msgs.clone());
+                pending.more |= more;
+            }
+            P::Error { code, .. } if *code == KEY_DOES_NOT_EXIST => {}
+            // Anything else, say a timeout or lin-kv being temporarily unavailable, changed
+            // nothing that matters to a read, so read again
+            P::Error { .. } => return self.send(outstanding),
+            _ => panic!("NOT ALLOWED: {:?}", message),
+        }
+        pending.remaining -= 1;
+        self.finish(id)
+    }
+}
+
+/// What the main loop reacts to
+enum Event {
+    Message(serde_json::Result<Message<P>>),
+    Tick,
+}
+
+fn main() -> serde_json::Result<()> {
+    let (server, mut sender) = server::init()?;
+    sender.clock = clock::from_config(
+        &std::env::var(CLOCK_VAR).unwrap_or_default(),
+        &sender.node_id,
+    );
+    let mut ctx = Context {
+        sender,
+        logs: HashMap::new(),
+        forwarded: HashMap::new(),
+        drop_committed: std::env::var(RETENTION_VAR).as_deref() == Ok("committed"),
+        allocator: offsets::from_config(&std::env::var(OFFSETS_VAR).unwrap_or_default()),
+        limits: poll::Limits::from_env(),
+        pending: HashMap::new(),
+        outstanding: HashMap::new(),
+        next_pending: 0,
+    };
+    // Messages and ticks both arrive here, so that the loop below has the context to itself
+    let (events_in, events) = mpsc::channel();
+    let ticks = events_in.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(TICK_INTERVAL);
+        if ticks.send(Event::Tick).is_err() {
+            break;
+        }
+    });
+    std::thread::spawn(move || loop {
+        let message = server.read_message();
+        let failed = message.is_err();
+        if events_in.send(Event::Message(message)).is_err() || failed {
+            break;
+        }
+    });
+    for event in events {
+        let message = match event {
+            Event::Message(message) => message?,
+            Event::Tick => {
+                ctx.retry()?;
+                continue;
+            }
+        };
+        ctx.sender.observe(&message);
+        if message.body.in_reply_to.is_some() {
+            ctx.reply(&message)?;
+            continue;
+        }
+        match &message.body.fields {
+            P::Send { key, msg, id } if ctx.owns(key) => {
+                let offset = match id.as_ref().and_then(|id| ctx.forwarded.get(id)) {
+                    Some(&offset) => offset,
+                    None => ctx.append(key, *msg),
+                };
+                if let Some(id) = id {
+                    ctx.forwarded.insert(id.clone(), offset);
+                }
+                ctx.sender.respond(&message, &P::SendOk { offset })?;
+            }
+            P::Send { key, msg, .. } => {
+                let id = ctx.start(Context::pending(&message));
+                let owner = owner(key, &ctx.sender.node_ids).to_string();
+                let send = P::Send {
+                    key: key.clone(),
+                    msg: *msg,
+                    id: Some(format!("{}/{}", ctx.sender.node_id, id)),
+                };
+                ctx.request(id, &owner, send, Some(key.clone()))?;
+                ctx.finish(id)?;
+            }
+            // Peers only ask for keys we own
+            P::Poll {
+                offsets,
+                max_messages,
+                max_bytes,
+            } if ctx.sender.node_ids.contains(&message.src) => {
+                let limits = ctx.limits.request(*max_messages, *max_bytes);
+                let (msgs, more) = ctx.poll_local(offsets, limits);
+                ctx.sender.respond(&message, &P::PollOk { msgs, more })?;
+            }
+            P::Poll {
+                offsets,
+                max_messages,
+                max_bytes,
+            } => {
+                let limits = ctx.limits.request(*max_messages, *max_bytes);
+                let mut by_owner = by_owner(offsets, &ctx.sender.node_ids);
+                let mut pending = Context::pending(&message);
+                if let Some(ours) = by_owner.remove(&ctx.sender.node_id) {
+                    (pending.msgs, pending.more) = ctx.poll_local(&ours, limits);
+                }
+                let id = ctx.start(pending);
+                for (owner, offsets) in by_owner {
+                    let poll = P::Poll {
+                        offsets,
+                        max_messages: Some(limits.max_messages),
+                        max_bytes: Some(limits.max_bytes),
+                    };
+                    ctx.request(id, &owner, poll, None)?;
+                }
+                ctx.finish(id)?;
+            }
+            P::CommitOffsets { offsets } => {
+                let id = ctx.start(Context::pending(&message));
+                for key in offsets.keys() {
+                    let read = P::Read {
+                        key: commit_key(key),
+                    };
+                    ctx.request(id, "lin-kv", read, Some(key.clone()))?;
+                }
+                ctx.finish(id)?;
+            }
+            P::ListCommittedOffsets { keys } => {
+                let id = ctx.start(Context::pending(&message));
+                for key in keys {
+                    let read = P::Read {
+                        key: commit_key(key),
+                    };
+                    ctx.request(id, "lin-kv", read, Some(key.clone()))?;
+                }
+                ctx.finish(id)?;
+            }
+            _ => panic!("NOT ALLOWED: {:?}", message),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("n{}", i)).collect()
+    }
+
+    #[test]
+    fn owner_is_a_stable_member_of_the_cluster() {
+        let node_ids = nodes(3);
+        for i in 0..100 {
+            let key = i.to_string();
+            let owner = owner(&key, &node_ids);
+            assert!(node_ids.iter().any(|n| n == owner));
+            assert_eq!(owner, self::owner(&key, &node_ids.clone()));
+        }
+    }
+
+    #[test]
+    fn keys_are_spread_over_every_node() {
+        let node_ids = nodes(3);
+        let offsets: HashMap<String, Offset> = (0..100).map(|i| (i.to_string(), 0)).collect();
+        let by_owner = by_owner(&offsets, &node_ids);
+        assert_eq!(by_owner.len(), 3);
+        assert_eq!(by_owner.values().map(HashMap::len).sum::<usize>(), 100);
+    }
+
+    #[test]
+    fn polls_are_split_by_owner() {
+        let node_ids = nodes(2);
+        let offsets: HashMap<String, Offset> = (0..20).map(|i| (i.to_string(), i)).collect();
+        for (owner, owned) in by_owner(&offsets, &node_ids) {
+            for (key, offset) in owned {
+                assert_eq!(self::owner(&key, &node_ids), owner);
+                assert_eq!(offsets[&key], offset);
+            }
+        }
+    }
+
+    #[test]
+    fn a_single_node_owns_everything() {
+        let node_ids = nodes(1);
+        assert!((0..10).all(|i| owner(&i.to_string(), &node_ids) == "n0"));
+    }
+
+    #[test]
+    fn commits_only_raise_the_offset() {
+        let read_ok = |value| P::ReadOk { value };
+        assert!(commit_step("k", 5, &read_ok(7)).is_none());
+        assert!(commit_step("k", 5, &read_ok(5)).is_none());
+        assert!(matches!(
+            commit_step("k", 5, &read_ok(3)),
+            Some(P::Cas {
+                from: 3,
+                to: 5,
+                create_if_not_exists: false,
+                ..
+            })
+        ));
+        assert!(commit_step("k", 5, &P::CasOk {}).is_none());
+    }
+
+    #[test]
+    fn failed_commits_start_over_from_a_read() {
+        let error = |code| P::Error {
+            code,
+            text: String::new(),
+        };
+        assert!(matches!(
+            commit_step("k", 5, &error(KEY_DOES_NOT_EXIST)),
+            Some(P::Cas {
+                from: 5,
+                to: 5,
+                create_if_not_exists: true,
+                ..
+            })
+        ));
+        // A CAS that lost, a timeout, and lin-kv being temporarily unavailable
+        for code in [22, 0, 11] {
+            assert!(matches!(
+                commit_step("k", 5, &error(code)),
+                Some(P::Read { key }) if key == "commit/k"
+            ));
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Strategies for handing out offsets.
+//!
+//! An offset only depends on the last offset in the key's log, and on the clock for the clocked
+//! strategy, so every strategy hands out strictly increasing offsets per key without keeping any
+//! state of its own. Whoever holds the log can carry on allocating, whether that is the same
+//! node after a restart or a new owner that has caught up with the log.
+use crate::clock::Clock;
+use crate::Offset;
+
+pub trait OffsetAllocator: Send {
+    /// The offset for a new entry in a log whose last entry has offset `last`
+    fn allocate(&self, last: Option<Offset>, clock: &mut dyn Clock) -> Offset;
+}
+
+/// Pick a strategy by name: "dense", "sparse" or "clock", the default
+pub fn from_config(name: &str) -> Box<dyn OffsetAllocator> {
+    match name {
+        "dense" => Box::new(Dense),
+        "sparse" => Box::new(Sparse { stride: 10 }),
+        _ => Box::new(Clocked),
+    }
+}
+
+/// 0, 1, 2, ... so that consumers can tell when they have missed an entry
+pub struct Dense;
+
+impl OffsetAllocator for Dense {
+    fn allocate(&self, last: Option<Offset>, _clock: &mut dyn Clock) -> Offset {
+        last.map_or(0, |last| last + 1)
+    }
+}
+
+/// Leaves `stride - 1` free offsets after each entry, for entries inserted later
+pub struct Sparse {
+    pub stride: Offset,
+}
+
+impl OffsetAllocator for Sparse {
+    fn allocate(&self, last: Option<Offset>, _clock: &mut dyn Clock) -> Offset {
+        last.map_or(0, |last| last + self.stride)
+    }
+}
+
+/// The clock's time, so offsets of causally related sends are ordered across keys too. Bumped
+/// past the last offset if the clock is behind it, say after a restart or when two ticks map to
+/// the same scalar.
+pub struct Clocked;
+
+impl OffsetAllocator for Clocked {
+    fn allocate(&self, last: Option<Offset>, clock: &mut dyn Clock) -> Offset {
+        let time = clock.tick().scalar();
+        last.map_or(time, |last| time.max(last + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::clock::{self, Timestamp};
+
+    /// Something that can happen to a node between sends
+    #[derive(Debug, Clone)]
+    enum Event {
+        Send {
+            key: u8,
+        },
+        /// A message from another node, whose clock may be ahead or behind
+        Observe {
+            time: u64,
+        },
+        /// The owning node starts over with a fresh clock, but keeps its logs
+        Restart,
+        /// The other node takes over every key, with the logs so far but its own clock
+        Handover,
+    }
+
+    fn event() -> impl Strategy<Value = Event> {
+        prop_oneof![
+            4 => (0..4_u8).prop_map(|key| Event::Send { key }),
+            1 => any::<u32>().prop_map(|time| Event::Observe { time: time as u64 }),
+            1 => Just(Event::Restart),
+            1 => Just(Event::Handover),
+        ]
+    }
+
+    /// Offsets per key after running through the events
+    fn run(
+        allocator: &dyn OffsetAllocator,
+        clock: &str,
+        events: &[Event],
+    ) -> HashMap<u8, Vec<Offset>> {
+        let mut logs = HashMap::<u8, Vec<Offset>>::new();
+        let nodes = ["n0", "n1"];
+        let mut clocks = nodes.map(|node| clock::from_config(clock, node));
+        let mut owner = 0;
+        for event in events {
+            let node_clock = &mut clocks[owner];
+            match event {
+                Event::Send { key } => {
+                    let log = logs.entry(*key).or_default();
+                    let offset = allocator.allocate(log.last().copied(), node_clock.as_mut());
+                    log.push(offset);
+                }
+                Event::Observe { time } => {
+                    let time = match clock {
+                        "lamport" => Timestamp::Lamport(*time),
+                        "vector" => Timestamp::Vector(clock::VectorTimestamp(
+                            [("n2".to_string(), *time)].into(),
+                        )),
+                        _ => Timestamp::Hybrid(clock::HybridTimestamp(*time, 0)),
+                    };
+                    node_clock.observe(&time);
+                }
+                Event::Restart => *node_clock = clock::from_config(clock, nodes[owner]),
+                Event::Handover => owner = 1 - owner,
+            }
+        }
+        logs
+    }
+
+    proptest! {
+        #[test]
+        fn offsets_strictly_increase_per_key(
+            strategy in prop_oneof![Just("dense"), Just("sparse"), Just("clock")],
+            clock in prop_oneof![Just("lamport"), Just("vector"), Just("hybrid")],
+            events in prop::collection::vec(event(), 0..200),
+        ) {
+            let logs = run(from_config(strategy).as_ref(), clock, &events);
+            for offsets in logs.values() {
+                prop_assert!(offsets.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", offsets);
+            }
+        }
+
+        #[test]
+        fn offsets_strictly_increase_across_an_owner_change(
+            strategy in prop_oneof![Just("dense"), Just("sparse"), Just("clock")],
+            clock in prop_oneof![Just("lamport"), Just("vector"), Just("hybrid")],
+            before in prop::collection::vec(event(), 0..100),
+            after in prop::collection::vec(event(), 0..100),
+        ) {
+            let events: Vec<Event> = before
+                .into_iter()
+                .chain([Event::Handover])
+                .chain(after)
+                .collect();
+            let logs = run(from_config(strategy).as_ref(), clock, &events);
+            for offsets in logs.values() {
+                prop_assert!(offsets.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", offsets);
+            }
+        }
+
+        #[test]
+        fn dense_offsets_have_no_gaps(events in prop::collection::vec(event(), 0..200)) {
+            let logs = run(&Dense, "lamport", &events);
+            for offsets in logs.values() {
+                prop_assert_eq!(offsets, &(0..offsets.len() as Offset).collect::<Vec<_>>());
+            }
+        }
+
+        #[test]
+        fn sparse_offsets_leave_room_between_entries(
+            stride in 1..100_i64,
+            events in prop::collection::vec(event(), 0..200),
+        ) {
+            let logs = run(&Sparse { stride }, "lamport", &events);
+            for offsets in logs.values() {
+                prop_assert!(offsets.windows(2).all(|pair| pair[1] - pair[0] == stride));
+            }
+        }
+    }
+}
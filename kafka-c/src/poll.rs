@@ -0,0 +1,152 @@
+//! Limits on how much a poll returns.
+//!
+//! The limits are for the whole response rather than each key, so when the budget runs out it is
+//! shared fairly: keys take one entry each in turn, and no key is starved by another with a long
+//! backlog.
+use std::collections::HashMap;
+
+use crate::{Entry, Offset};
+
+/// Environment variables setting the default limits, which requests can only lower
+const MAX_MESSAGES_VAR: &str = "KAFKA_POLL_MAX_MESSAGES";
+const MAX_BYTES_VAR: &str = "KAFKA_POLL_MAX_BYTES";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// At least 1, since a poll that could never return anything would always say there is more
+    pub max_messages: usize,
+    /// Of the entries as JSON. A response always has at least one entry if any are available,
+    /// so that an entry bigger than the limit cannot block a consumer forever.
+    pub max_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_messages: 100,
+            max_bytes: usize::MAX,
+        }
+    }
+}
+
+impl Limits {
+    pub fn from_env() -> Limits {
+        let default = Limits::default();
+        let var = |name: &str, default: usize| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        Limits {
+            max_messages: var(MAX_MESSAGES_VAR, default.max_messages).max(1),
+            max_bytes: var(MAX_BYTES_VAR, default.max_bytes),
+        }
+    }
+    /// These limits, lowered to whatever a request asked for
+    pub fn request(self, max_messages: Option<usize>, max_bytes: Option<usize>) -> Limits {
+        Limits {
+            max_messages: self
+                .max_messages
+                .min(max_messages.unwrap_or(usize::MAX))
+                .max(1),
+            max_bytes: self.max_bytes.min(max_bytes.unwrap_or(usize::MAX)),
+        }
+    }
+}
+
+/// Take entries from each key's available entries in turn until the limits are hit. Also returns
+/// whether entries were left behind.
+pub fn take(
+    available: Vec<(String, Vec<(Offset, Entry)>)>,
+    limits: Limits,
+) -> (HashMap<String, Vec<(Offset, Entry)>>, bool) {
+    let mut msgs: HashMap<String, Vec<(Offset, Entry)>> = available
+        .iter()
+        .map(|(key, _)| (key.clone(), vec![]))
+        .collect();
+    let (mut messages, mut bytes) = (0, 0);
+    for round in 0.. {
+        let mut took = false;
+        for (key, entries) in &available {
+            let Some(entry) = entries.get(round) else {
+                continue;
+            };
+            let size = serde_json::to_string(entry).map_or(0, |json| json.len());
+            let fits = messages < limits.max_messages
+                && (messages == 0 || bytes + size <= limits.max_bytes);
+            if !fits {
+                return (msgs, true);
+            }
+            msgs.get_mut(key).unwrap().push(*entry);
+            messages += 1;
+            bytes += size;
+            took = true;
+        }
+        if !took {
+            break;
+        }
+    }
+    (msgs, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(len: usize) -> Vec<(Offset, Entry)> {
+        (0..len).map(|i| (i as Offset, i)).collect()
+    }
+
+    #[test]
+    fn budget_is_shared_between_keys() {
+        let (long, short) = (log(50), log(2));
+        let limits = Limits {
+            max_messages: 6,
+            max_bytes: usize::MAX,
+        };
+        let (msgs, more) = take(
+            vec![("a".into(), long), ("b".into(), short.clone())],
+            limits,
+        );
+        assert!(more);
+        assert_eq!(msgs["a"].len(), 4);
+        assert_eq!(msgs["b"], short);
+    }
+
+    #[test]
+    fn byte_limit_still_returns_one_entry() {
+        let entries = log(3);
+        let limits = Limits {
+            max_messages: 10,
+            max_bytes: 1,
+        };
+        let (msgs, more) = take(vec![("a".into(), entries)], limits);
+        assert!(more);
+        assert_eq!(msgs["a"], vec![(0, 0)]);
+    }
+
+    #[test]
+    fn everything_fits() {
+        let entries = log(3);
+        let (msgs, more) = take(vec![("a".into(), entries.clone())], Limits::default());
+        assert!(!more);
+        assert_eq!(msgs["a"], entries);
+    }
+
+    #[test]
+    fn requests_can_only_lower_limits() {
+        let limits = Limits::default().request(Some(1_000), Some(5));
+        assert_eq!(limits.max_messages, Limits::default().max_messages);
+        assert_eq!(limits.max_bytes, 5);
+    }
+
+    #[test]
+    fn requests_always_allow_one_message() {
+        let limits = Limits::default().request(Some(0), None);
+        assert_eq!(limits.max_messages, 1);
+        let (msgs, more) = take(vec![("a".into(), log(2))], limits);
+        assert!(more);
+        assert_eq!(msgs["a"], vec![(0, 0)]);
+    }
+}
@@ -0,0 +1,145 @@
+use std::{collections::hash_map::DefaultHasher, io::Write};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Result;
+use std::hash::{Hash, Hasher};
+
+use crate::clock::{Clock, HybridClock, Timestamp};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message<T> {
+    pub src: String,
+    pub dest: String,
+    pub body: Body<T>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Body<T> {
+    pub msg_id: Option<u64>,
+    pub in_reply_to: Option<u64>,
+    /// The sender's logical time, absent on messages from clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock: Option<Timestamp>,
+    #[serde(flatten)]
+    pub fields: T,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum InitPayload {
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk {},
+}
+
+pub struct Server {}
+
+impl Server {
+    fn init() -> (Server, Message<InitPayload>) {
+        let server = Server {};
+        let init_message: Message<InitPayload> = server.read_message().unwrap();
+        (server, init_message)
+    }
+    pub fn read_message<T: DeserializeOwned>(&self) -> Result<Message<T>> {
+        let stdin = std::io::stdin().lock();
+        let mut deserializer = serde_json::Deserializer::from_reader(stdin);
+        Message::deserialize(&mut deserializer)
+    }
+}
+
+pub struct Sender {
+    pub node_id: String,
+    pub node_ids: Vec<String>,
+    /// Stamped on every message to another node. Hybrid unless the workload picks another.
+    pub clock: Box<dyn Clock>,
+    counter: u64,
+}
+
+impl Sender {
+    /// Catch the clock up with a message that was read
+    pub fn observe<T>(&mut self, message: &Message<T>) {
+        if let Some(time) = &message.body.clock {
+            self.clock.observe(time);
+        }
+    }
+    fn init(init_message: &Message<InitPayload>) -> Result<Sender> {
+        let mut sender = match &init_message.body.fields {
+            InitPayload::Init { node_id, node_ids } => {
+                // Calculate a unique starting counter index using the hash of the node ID
+                let mut hasher = DefaultHasher::new();
+                node_id.hash(&mut hasher);
+                let counter = hasher.finish();
+                Sender {
+                    node_id: node_id.clone(),
+                    node_ids: node_ids.clone(),
+                    clock: Box::<HybridClock>::default(),
+                    counter,
+                }
+            }
+            _ => panic!("Invalid init message"),
+        };
+        let init_ok = InitPayload::InitOk {};
+        sender.respond(init_message, init_ok)?;
+        Ok(sender)
+    }
+    /// Write a message directly to stdout
+    pub fn send_message<T: Serialize>(&self, message: &Message<T>) -> Result<()> {
+        let stdout = std::io::stdout().lock();
+        let mut serializer = serde_json::Serializer::new(stdout);
+        message.serialize(&mut serializer)?;
+        serializer
+            .into_inner()
+            .write_all(b"\n")
+            .expect("Error writing newline");
+        Ok(())
+    }
+    /// Adds the msg_id field to a body, and the clock field if it is going to another node, and
+    /// wraps it in a Message
+    pub fn message<T: Serialize>(&mut self, to: &str, fields: T) -> Result<Message<T>> {
+        let msg_id = self.counter;
+        self.counter += 1;
+        let body = Body {
+            msg_id: Some(msg_id),
+            in_reply_to: None,
+            clock: self
+                .node_ids
+                .iter()
+                .any(|node| node == to)
+                .then(|| self.clock.tick()),
+            fields,
+        };
+        Ok(Message {
+            src: self.node_id.clone(),
+            dest: to.to_string(),
+            body,
+        })
+    }
+    /// Creates a response to a message by setting the msg_id and in_reply_to fields
+    pub fn response<T, U>(&mut self, to: &Message<T>, fields: U) -> Result<Message<U>>
+    where
+        T: Serialize,
+        U: Serialize,
+    {
+        let mut message = self.message(&to.src, fields)?;
+        message.body.in_reply_to = to.body.msg_id;
+        Ok(message)
+    }
+    /// Respond to a message. If the message has a msg_id, set the in_reply_to appropriately
+    pub fn respond<T: Serialize, U: Serialize>(
+        &mut self,
+        to: &Message<T>,
+        fields: U,
+    ) -> Result<()> {
+        let message = self.response(to, fields)?;
+        self.send_message(&message)
+    }
+}
+
+pub fn init() -> Result<(Server, Sender)> {
+    let (server, init_message) = Server::init();
+    let sender = Sender::init(&init_message)?;
+    Ok((server, sender))
+}
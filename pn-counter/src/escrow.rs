@@ -0,0 +1,160 @@
+//! Escrow for bounded counters: keeping the value from ever going below zero, or above a limit,
+//! without coordinating on every change.
+//!
+//! Every increment gives the node that made it the right to take the same amount away again. A
+//! node only decrements within its own rights, and its rights only depend on counts that it alone
+//! changes or that only ever grow, so the rights of all nodes sum to the value, and no
+//! interleaving of decrements can take the value below zero. A node that runs short asks its
+//! peers to transfer some of theirs.
+//!
+//! An upper limit is kept with increments and decrements swapped. The room below the limit
+//! starts out shared between the nodes, every decrement adds room for the node that made it, and
+//! a node only increments within its own room. Room is transferred separately from rights.
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::GCounter;
+
+/// How much each node has given each other node, by giver. Only the giver changes its own row.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct Transfers(HashMap<String, GCounter>);
+
+impl Transfers {
+    pub fn give(&mut self, from: &str, to: &str, amount: u64) {
+        self.0.entry(from.to_string()).or_default().add(to, amount);
+    }
+    pub fn merge(&mut self, other: Transfers) {
+        for (giver, given) in other.0 {
+            self.0.entry(giver).or_default().merge(given);
+        }
+    }
+    /// Everything `node` has given away
+    fn given(&self, node: &str) -> u64 {
+        self.0.get(node).map_or(0, GCounter::value)
+    }
+    /// Everything `node` has been given
+    fn received(&self, node: &str) -> u64 {
+        self.0.values().map(|given| given.get(node)).sum()
+    }
+}
+
+/// How much `node` may decrement by. Never more than the node really has: other nodes only ever
+/// add to what it has received, and everything else it changes itself.
+pub fn rights(
+    node: &str,
+    increments: &GCounter,
+    decrements: &GCounter,
+    transfers: &Transfers,
+) -> u64 {
+    (increments.get(node) + transfers.received(node))
+        .saturating_sub(decrements.get(node) + transfers.given(node))
+}
+
+/// How much `node` may increment by without the value going over `limit`. `transfers` are of
+/// room, not of rights.
+pub fn room(
+    node: &str,
+    node_ids: &[String],
+    limit: u64,
+    increments: &GCounter,
+    decrements: &GCounter,
+    transfers: &Transfers,
+) -> u64 {
+    (share(node, node_ids, limit) + decrements.get(node) + transfers.received(node))
+        .saturating_sub(increments.get(node) + transfers.given(node))
+}
+
+/// Whether adding `delta` to `value` keeps it between zero and `limit`, if there is one. A
+/// value that would not fit in an i64 is out of bounds too.
+pub fn in_bounds(value: i64, delta: i64, limit: Option<u64>) -> bool {
+    let Some(after) = value.checked_add(delta) else {
+        return false;
+    };
+    let limit = limit.map_or(i64::MAX, |limit| i64::try_from(limit).unwrap_or(i64::MAX));
+    (0..=limit).contains(&after)
+}
+
+/// The room below `limit` that `node` starts out with: an even share, with the remainder going
+/// to the first node
+fn share(node: &str, node_ids: &[String], limit: u64) -> u64 {
+    let Some(index) = node_ids.iter().position(|n| n == node) else {
+        return 0;
+    };
+    let count = node_ids.len() as u64;
+    limit / count + if index == 0 { limit % count } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn increments_give_rights_to_their_node() {
+        let (mut increments, mut decrements) = (GCounter::default(), GCounter::default());
+        let transfers = Transfers::default();
+        increments.add("n0", 5);
+        decrements.add("n0", 2);
+        assert_eq!(rights("n0", &increments, &decrements, &transfers), 3);
+        assert_eq!(rights("n1", &increments, &decrements, &transfers), 0);
+    }
+
+    #[test]
+    fn transfers_move_rights() {
+        let (mut increments, decrements) = (GCounter::default(), GCounter::default());
+        let mut transfers = Transfers::default();
+        increments.add("n0", 5);
+        transfers.give("n0", "n1", 4);
+        assert_eq!(rights("n0", &increments, &decrements, &transfers), 1);
+        assert_eq!(rights("n1", &increments, &decrements, &transfers), 4);
+    }
+
+    #[test]
+    fn merging_transfers_is_idempotent() {
+        let mut ours = Transfers::default();
+        ours.give("n0", "n1", 2);
+        let mut theirs = ours.clone();
+        theirs.give("n0", "n1", 3);
+        theirs.give("n2", "n1", 1);
+        ours.merge(theirs.clone());
+        ours.merge(theirs);
+        assert_eq!(ours.given("n0"), 5);
+        assert_eq!(ours.received("n1"), 6);
+    }
+
+    #[test]
+    fn room_starts_shared_and_tracks_the_value() {
+        let nodes: Vec<String> = ["n0", "n1", "n2"].map(String::from).into();
+        let (mut increments, mut decrements) = (GCounter::default(), GCounter::default());
+        let transfers = Transfers::default();
+        let room = |node, increments: &GCounter, decrements: &GCounter| {
+            room(node, &nodes, 10, increments, decrements, &transfers)
+        };
+        assert_eq!(room("n0", &increments, &decrements), 4);
+        assert_eq!(room("n1", &increments, &decrements), 3);
+        assert_eq!(room("n3", &increments, &decrements), 0);
+        increments.add("n1", 3);
+        assert_eq!(room("n1", &increments, &decrements), 0);
+        decrements.add("n2", 5);
+        assert_eq!(room("n2", &increments, &decrements), 8);
+        let total: u64 = nodes
+            .iter()
+            .map(|node| room(node, &increments, &decrements))
+            .sum();
+        assert_eq!(total as i64, 10 - (3 - 5));
+    }
+
+    #[test]
+    fn bounds_hold_for_extreme_deltas() {
+        assert!(in_bounds(5, -5, None));
+        assert!(!in_bounds(5, -6, Some(10)));
+        assert!(in_bounds(5, 5, Some(10)));
+        assert!(!in_bounds(5, 6, Some(10)));
+        assert!(!in_bounds(1, i64::MAX, None));
+        assert!(!in_bounds(1, i64::MAX, Some(u64::MAX)));
+        assert!(in_bounds(0, i64::MAX, Some(u64::MAX)));
+        assert!(!in_bounds(0, i64::MIN, None));
+        assert!(!in_bounds(-1, i64::MIN, None));
+        assert!(in_bounds(i64::MAX, i64::MIN + 1, Some(u64::MAX)));
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use escrow::Transfers;
+use serde::{Deserialize, Serialize};
+use server::{Message, Sender};
+
+mod escrow;
+mod server;
+
+/// How often every node sends its counts to every other node
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Environment variable that, set to "bounded", keeps the value from going below zero, see
+/// escrow.rs
+const MODE_VAR: &str = "PN_COUNTER_MODE";
+/// Environment variable that, set to a number, keeps the value from going above it
+const LIMIT_VAR: &str = "PN_COUNTER_LIMIT";
+
+/// Error codes for a rejected add: precondition-failed when the whole counter could not take
+/// it, temporarily-unavailable when it could but this node's rights or room do not, yet
+const PRECONDITION_FAILED: u64 = 22;
+const TEMPORARILY_UNAVAILABLE: u64 = 11;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum P {
+    Add {
+        delta: i64,
+    },
+    AddOk {},
+    Read {},
+    ReadOk {
+        value: i64,
+    },
+    /// Every count the sender knows of
+    Merge {
+        increments: GCounter,
+        decrements: GCounter,
+        #[serde(default)]
+        transfers: Transfers,
+        #[serde(default)]
+        room_transfers: Transfers,
+    },
+    /// A bounded node asking a peer for rights to decrement, or room to increment, by `amount`
+    QuotaRequest {
+        amount: u64,
+        #[serde(default)]
+        direction: Direction,
+    },
+    Error {
+        code: u64,
+        text: String,
+    },
+}
+
+/// Which way an add moves the value
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Direction {
+    #[default]
+    Down,
+    Up,
+}
+
+/// A grow-only count per node, merged by taking the larger of each
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct GCounter(HashMap<String, u64>);
+
+impl GCounter {
+    fn add(&mut self, node: &str, delta: u64) {
+        *self.0.entry(node.to_string()).or_default() += delta;
+    }
+    fn merge(&mut self, other: GCounter) {
+        for (node, count) in other.0 {
+            let ours = self.0.entry(node).or_default();
+            *ours = (*ours).max(count);
+        }
+    }
+    fn value(&self) -> u64 {
+        self.0.values().sum()
+    }
+    fn get(&self, node: &str) -> u64 {
+        self.0.get(node).copied().unwrap_or_default()
+    }
+}
+
+/// A PN-counter: increments and decrements are kept in separate G-counters, which can each only
+/// grow and so merge safely, and the value is their difference
+struct Context {
+    sender: Sender,
+    increments: GCounter,
+    decrements: GCounter,
+    /// Only used when bounded
+    transfers: Transfers,
+    bounded: bool,
+    /// Transfers of room below the limit, only used when there is one
+    room_transfers: Transfers,
+    limit: Option<u64>,
+}
+
+impl Context {
+    fn merge(&self) -> P {
+        P::Merge {
+            increments: self.increments.clone(),
+            decrements: self.decrements.clone(),
+            transfers: self.transfers.clone(),
+            room_transfers: self.room_transfers.clone(),
+        }
+    }
+    fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+    /// How far this node may move the value in a direction, or None if it is unbounded that way
+    fn rights(&self, direction: Direction) -> Option<u64> {
+        self.rights_of(&self.sender.node_id, direction)
+    }
+    /// How far a node may move the value, as far as we know
+    fn rights_of(&self, node_id: &str, direction: Direction) -> Option<u64> {
+        match direction {
+            Direction::Down => self.bounded.then(|| {
+                escrow::rights(node_id, &self.increments, &self.decrements, &self.transfers)
+            }),
+            Direction::Up => self.limit.map(|limit| {
+                escrow::room(
+                    node_id,
+                    &self.sender.node_ids,
+                    limit,
+                    &self.increments,
+                    &self.decrements,
+                    &self.room_transfers,
+                )
+            }),
+        }
+    }
+    /// Why an add of `delta` cannot go ahead, if it cannot. Peers are asked for the rights or
+    /// room missing either way, since our view of the value can be behind. The peers that seem
+    /// to have the most are asked first, and anything they do not seem to have is split evenly,
+    /// so that between them they do not give much more than is missing.
+    fn reject(&mut self, delta: i64) -> serde_json::Result<Option<P>> {
+        let (direction, amount) = if delta < 0 {
+            (Direction::Down, delta.unsigned_abs())
+        } else {
+            (Direction::Up, delta as u64)
+        };
+        let Some(rights) = self.rights(direction) else {
+            return Ok(None);
+        };
+        if amount <= rights {
+            return Ok(None);
+        }
+        let mut peers: Vec<(String, u64)> = self
+            .peers()
+            .map(|peer| {
+                let theirs = self.rights_of(&peer, direction).unwrap_or(0);
+                (peer, theirs)
+            })
+            .collect();
+        peers.sort_by_key(|(_, theirs)| std::cmp::Reverse(*theirs));
+        let mut missing = amount - rights;
+        let mut asks = vec![];
+        for (peer, theirs) in peers {
+            let ask = missing.min(theirs);
+            missing -= ask;
+            asks.push((peer, ask));
+        }
+        let part = missing.div_ceil(asks.len().max(1) as u64);
+        for (peer, ask) in asks {
+            if ask + part > 0 {
+                let request = P::QuotaRequest {
+                    amount: ask + part,
+                    direction,
+                };
+                self.sender.send(&peer, request)?;
+            }
+        }
+        let value = self.value();
+        let possible = escrow::in_bounds(value, delta, self.limit);
+        Ok(Some(if possible {
+            P::Error {
+                code: TEMPORARILY_UNAVAILABLE,
+                text: format!("only {} can be added here, asking peers for more", rights),
+            }
+        } else {
+            P::Error {
+                code: PRECONDITION_FAILED,
+                text: format!("the counter is {}", value),
+            }
+        }))
+    }
+    fn peers(&self) -> impl Iterator<Item = String> + '_ {
+        self.sender
+            .node_ids
+            .iter()
+            .filter(|n| **n != self.sender.node_id)
+            .cloned()
+    }
+}
+
+fn main() -> serde_json::Result<()> {
+    let (server, sender) = server::init()?;
+    let context: Arc<Mutex<Context>> = Arc::new(Mutex::new(Context {
+        sender,
+        increments: GCounter::default(),
+        decrements: GCounter::default(),
+        transfers: Transfers::default(),
+        bounded: std::env::var(MODE_VAR).as_deref() == Ok("bounded"),
+        room_transfers: Transfers::default(),
+        limit: std::env::var(LIMIT_VAR)
+            .ok()
+            .and_then(|limit| limit.parse().ok()),
+    }));
+    let thread_context = context.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(GOSSIP_INTERVAL);
+        let mut ctx = thread_context.lock().unwrap();
+        let ctx = ctx.deref_mut();
+        let peers: Vec<String> = ctx.peers().collect();
+        for peer in peers {
+            let merge = ctx.merge();
+            ctx.sender
+                .send(&peer, &merge)
+                .expect("Error sending gossip");
+        }
+    });
+    loop {
+        let message: Message<P> = server.read_message()?;
+        let mut ctx = context.lock().unwrap();
+        let ctx = ctx.deref_mut();
+        match message.body.fields {
+            P::Add { delta } => {
+                if let Some(error) = ctx.reject(delta)? {
+                    ctx.sender.respond(&message, &error)?;
+                    continue;
+                }
+                let node_id = &ctx.sender.node_id;
+                if delta >= 0 {
+                    ctx.increments.add(node_id, delta.unsigned_abs());
+                } else {
+                    ctx.decrements.add(node_id, delta.unsigned_abs());
+                }
+                ctx.sender.respond(&message, &P::AddOk {})?
+            }
+            P::Read {} => {
+                let value = ctx.value();
+                ctx.sender.respond(&message, &P::ReadOk { value })?
+            }
+            P::Merge {
+                increments,
+                decrements,
+                transfers,
+                room_transfers,
+            } => {
+                ctx.increments.merge(increments);
+                ctx.decrements.merge(decrements);
+                ctx.transfers.merge(transfers);
+                ctx.room_transfers.merge(room_transfers);
+            }
+            // Give what we can spare straight away rather than waiting for the next gossip
+            P::QuotaRequest { amount, direction } => {
+                let amount = amount.min(ctx.rights(direction).unwrap_or(0));
+                if amount > 0 {
+                    let node_id = ctx.sender.node_id.clone();
+                    let transfers = match direction {
+                        Direction::Down => &mut ctx.transfers,
+                        Direction::Up => &mut ctx.room_transfers,
+                    };
+                    transfers.give(&node_id, &message.src, amount);
+                    let merge = ctx.merge();
+                    ctx.sender.send(&message.src, &merge)?;
+                }
+            }
+            _ => panic!("NOT ALLOWED"),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! Checks that every node finally reads the sum of the acknowledged adds, even when one node was
+//! partitioned off while the adds happened, and that a bounded counter never goes below zero or
+//! above its limit.
+mod common;
+
+use common::{eventually, Cluster, NODES};
+
+#[test]
+fn final_reads_equal_acknowledged_adds() {
+    let cluster = Cluster::start(&[]);
+    cluster.partitioned.lock().unwrap().push("n2".to_string());
+    let mut total = 0;
+    let mut partitioned_total = 0;
+    for i in 0..60_i64 {
+        let delta = if i % 3 == 0 { -(i % 7) } else { i % 5 };
+        let node = NODES[i as usize % NODES.len()];
+        assert!(cluster.add(node, delta));
+        total += delta;
+        if node == "n2" {
+            partitioned_total += delta;
+        }
+    }
+    // The partitioned node only knows of its own adds
+    assert_eq!(cluster.read("n2"), partitioned_total);
+    cluster.partitioned.lock().unwrap().clear();
+    cluster.converges(total);
+}
+
+#[test]
+fn bounded_counter_never_goes_below_zero() {
+    let cluster = Cluster::start(&[("PN_COUNTER_MODE", "bounded")]);
+    assert!(cluster.add("n0", 10));
+    let mut total = 10;
+    // Before n0's rights reach n1, n1 cannot take anything
+    assert!(!cluster.add("n1", -4));
+    // n0 transfers rights when asked, so a retry goes through. The first request only asked n0
+    // for part of them, since n1 did not know yet that n0 had any.
+    let taken = eventually(|| cluster.add("n1", -4).then_some(()));
+    assert!(taken.is_some(), "Rights were not transferred");
+    total -= 4;
+    for i in 0..30 {
+        let node = NODES[i % NODES.len()];
+        if cluster.add(node, -1) {
+            total -= 1;
+        }
+        assert!(cluster.read(node) >= 0);
+    }
+    assert!(total >= 0, "the counter went below zero");
+    cluster.converges(total);
+}
+
+#[test]
+fn limited_counter_never_goes_above_the_limit() {
+    let cluster = Cluster::start(&[("PN_COUNTER_LIMIT", "10")]);
+    let mut total = 0;
+    for i in 0..30 {
+        let node = NODES[i % NODES.len()];
+        let delta = if i % 5 == 4 { -1 } else { 1 };
+        if cluster.add(node, delta) {
+            total += delta;
+        }
+        assert!(cluster.read(node) <= 10);
+    }
+    assert!(total <= 10, "the counter went over its limit");
+    // No node starts out with room for all of it, so reaching the limit takes transfers
+    let mut i = 0;
+    let filled = eventually(|| {
+        i += 1;
+        if total < 10 && cluster.add(NODES[i % NODES.len()], 1) {
+            total += 1;
+        }
+        (total == 10).then_some(())
+    });
+    assert!(filled.is_some(), "room was not transferred: {}", total);
+    assert!(!cluster.add("n0", 1));
+    cluster.converges(total);
+}
@@ -0,0 +1,129 @@
+//! A small cluster of the real binary, relaying messages between the nodes in process, with a
+//! switch to partition nodes off. The same file is used by every counter crate's checker.
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+pub const NODES: [&str; 3] = ["n0", "n1", "n2"];
+/// How long anything that is expected to happen eventually may take
+const DEADLINE: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub struct Cluster {
+    children: Vec<Child>,
+    inputs: Arc<Mutex<HashMap<String, ChildStdin>>>,
+    /// Nodes whose messages to and from other nodes are dropped
+    pub partitioned: Arc<Mutex<Vec<String>>>,
+    replies: Receiver<Value>,
+}
+
+impl Cluster {
+    pub fn start(envs: &[(&str, &str)]) -> Cluster {
+        let inputs = Arc::new(Mutex::new(HashMap::new()));
+        let partitioned = Arc::new(Mutex::new(vec![]));
+        let (replies_in, replies) = channel();
+        let mut children = vec![];
+        for node in NODES {
+            let mut child = Command::new(env!("CARGO_BIN_EXE_echo"))
+                .envs(envs.iter().copied())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("Error starting node");
+            inputs
+                .lock()
+                .unwrap()
+                .insert(node.to_string(), child.stdin.take().unwrap());
+            let stdout = BufReader::new(child.stdout.take().unwrap());
+            let (inputs, partitioned, replies_in) =
+                (inputs.clone(), partitioned.clone(), replies_in.clone());
+            std::thread::spawn(move || {
+                for line in stdout.lines() {
+                    let message: Value = serde_json::from_str(&line.unwrap()).unwrap();
+                    let dest = message["dest"].as_str().unwrap().to_string();
+                    if !NODES.contains(&dest.as_str()) {
+                        replies_in.send(message["body"].clone()).unwrap();
+                        continue;
+                    }
+                    let partitioned = partitioned.lock().unwrap();
+                    let src = message["src"].as_str().unwrap().to_string();
+                    if !partitioned.contains(&src) && !partitioned.contains(&dest) {
+                        send(&inputs, &dest, &message);
+                    }
+                }
+            });
+            children.push(child);
+        }
+        let cluster = Cluster {
+            children,
+            inputs,
+            partitioned,
+            replies,
+        };
+        for node in NODES {
+            let body = json!({"type": "init", "msg_id": 0, "node_id": node, "node_ids": NODES});
+            assert_eq!(cluster.request(node, body)["type"], "init_ok");
+        }
+        cluster
+    }
+    /// Send a client request and wait for the reply
+    pub fn request(&self, node: &str, body: Value) -> Value {
+        let message = json!({"src": "c1", "dest": node, "body": body});
+        send(&self.inputs, node, &message);
+        self.replies
+            .recv_timeout(Duration::from_secs(5))
+            .expect("No reply")
+    }
+    /// Add to the counter through a node, returning whether the add was acknowledged
+    pub fn add(&self, node: &str, delta: i64) -> bool {
+        let reply = self.request(node, json!({"type": "add", "msg_id": 1, "delta": delta}));
+        reply["type"] == "add_ok"
+    }
+    pub fn read(&self, node: &str) -> i64 {
+        let reply = self.request(node, json!({"type": "read", "msg_id": 1}));
+        reply["value"].as_i64().unwrap()
+    }
+    /// Wait until every node reads `total`, failing with what they read if they never do
+    pub fn converges(&self, total: i64) {
+        let mut reads = vec![];
+        let converged = eventually(|| {
+            reads = NODES.iter().map(|node| self.read(node)).collect();
+            reads.iter().all(|&read| read == total).then_some(())
+        });
+        assert!(converged.is_some(), "expected {}, read {:?}", total, reads);
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+        }
+    }
+}
+
+fn send(inputs: &Mutex<HashMap<String, ChildStdin>>, node: &str, message: &Value) {
+    let mut inputs = inputs.lock().unwrap();
+    let input = inputs.get_mut(node).unwrap();
+    writeln!(input, "{}", message).unwrap();
+}
+
+/// Call `f` until it returns Some, giving up after DEADLINE
+pub fn eventually<T>(mut f: impl FnMut() -> Option<T>) -> Option<T> {
+    let deadline = Instant::now() + DEADLINE;
+    loop {
+        if let Some(result) = f() {
+            return Some(result);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}